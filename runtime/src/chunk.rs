@@ -1,8 +1,12 @@
+use std::fmt;
+use std::rc::Rc;
+
 use crate::common::{dissasemble_error, runtime_error};
-use crate::value::Value;
+use crate::object::{Obj, ObjFunction};
+use crate::value::{Value, ValueFormat, ValueType};
 
 #[repr(u8)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum OpCode {
     OpReturn = 0,
     OpConstant = 1,
@@ -18,6 +22,45 @@ pub enum OpCode {
     OpEqual = 11,
     OpGreater = 12,
     OpLess = 13,
+    OpType = 14,
+    OpShiftLeft = 15,
+    OpShiftRight = 16,
+    OpShiftRightLogical = 17,
+    OpRange = 18,
+    OpRangeInclusive = 19,
+    OpPop = 20,
+    OpJump = 21,
+    OpJumpIfNil = 22,
+    OpJumpIfFalse = 23,
+    OpLoop = 24,
+    OpPrint = 25,
+    OpPopN = 26,
+    OpGetLocal = 27,
+    OpSetLocal = 28,
+    OpIncrement = 29,
+    OpDecrement = 30,
+    OpCall = 31,
+    OpDefineGlobal = 32,
+    OpGetGlobal = 33,
+    OpSetGlobal = 34,
+    OpTailCall = 35,
+    OpModulo = 36,
+    OpZero = 37,
+    OpOne = 38,
+    OpWrite = 39,
+    OpPushFrame = 40,
+    OpPopFrame = 41,
+    OpDefineGlobalLong = 42,
+    OpGetGlobalLong = 43,
+    OpSetGlobalLong = 44,
+    OpContains = 45,
+    OpPrintMany = 46,
+    OpIs = 47,
+    // Emitted when the callee is a global named after a native.
+    OpCallNative = 48,
+    OpConstantLong = 49,
+    // Checks the type of the top of the stack, never emitted by the compiler.
+    OpAssertType = 50,
 }
 
 pub fn byte_to_op(byte: u8) -> Result<OpCode, String> {
@@ -36,6 +79,43 @@ pub fn byte_to_op(byte: u8) -> Result<OpCode, String> {
         11 => return Ok(OpCode::OpEqual),
         12 => return Ok(OpCode::OpGreater),
         13 => return Ok(OpCode::OpLess),
+        14 => return Ok(OpCode::OpType),
+        15 => return Ok(OpCode::OpShiftLeft),
+        16 => return Ok(OpCode::OpShiftRight),
+        17 => return Ok(OpCode::OpShiftRightLogical),
+        18 => return Ok(OpCode::OpRange),
+        19 => return Ok(OpCode::OpRangeInclusive),
+        20 => return Ok(OpCode::OpPop),
+        21 => return Ok(OpCode::OpJump),
+        22 => return Ok(OpCode::OpJumpIfNil),
+        23 => return Ok(OpCode::OpJumpIfFalse),
+        24 => return Ok(OpCode::OpLoop),
+        25 => return Ok(OpCode::OpPrint),
+        26 => return Ok(OpCode::OpPopN),
+        27 => return Ok(OpCode::OpGetLocal),
+        28 => return Ok(OpCode::OpSetLocal),
+        29 => return Ok(OpCode::OpIncrement),
+        30 => return Ok(OpCode::OpDecrement),
+        31 => return Ok(OpCode::OpCall),
+        32 => return Ok(OpCode::OpDefineGlobal),
+        33 => return Ok(OpCode::OpGetGlobal),
+        34 => return Ok(OpCode::OpSetGlobal),
+        35 => return Ok(OpCode::OpTailCall),
+        36 => return Ok(OpCode::OpModulo),
+        37 => return Ok(OpCode::OpZero),
+        38 => return Ok(OpCode::OpOne),
+        39 => return Ok(OpCode::OpWrite),
+        40 => return Ok(OpCode::OpPushFrame),
+        41 => return Ok(OpCode::OpPopFrame),
+        42 => return Ok(OpCode::OpDefineGlobalLong),
+        43 => return Ok(OpCode::OpGetGlobalLong),
+        44 => return Ok(OpCode::OpSetGlobalLong),
+        45 => return Ok(OpCode::OpContains),
+        46 => return Ok(OpCode::OpPrintMany),
+        47 => return Ok(OpCode::OpIs),
+        48 => return Ok(OpCode::OpCallNative),
+        49 => return Ok(OpCode::OpConstantLong),
+        50 => return Ok(OpCode::OpAssertType),
         _ => {
             return Err(runtime_error(format!(
                 "Invalid conversion to instruction from byte: '{}'\nInstruction doesn't exist.",
@@ -45,11 +125,276 @@ pub fn byte_to_op(byte: u8) -> Result<OpCode, String> {
     };
 }
 
+fn escape_json(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+// Number of operand bytes following the instruction in the code.
+pub fn operand_len(instruction: &OpCode) -> usize {
+    match instruction {
+        OpCode::OpConstant
+        | OpCode::OpPopN
+        | OpCode::OpPrintMany
+        | OpCode::OpGetLocal
+        | OpCode::OpSetLocal
+        | OpCode::OpIncrement
+        | OpCode::OpDecrement
+        | OpCode::OpCall
+        | OpCode::OpCallNative
+        | OpCode::OpAssertType
+        | OpCode::OpTailCall
+        | OpCode::OpDefineGlobal
+        | OpCode::OpGetGlobal
+        | OpCode::OpSetGlobal => return 1,
+        OpCode::OpJump | OpCode::OpJumpIfNil | OpCode::OpJumpIfFalse | OpCode::OpLoop => return 2,
+        OpCode::OpConstantLong
+        | OpCode::OpDefineGlobalLong
+        | OpCode::OpGetGlobalLong
+        | OpCode::OpSetGlobalLong => return 3,
+        _ => return 0,
+    }
+}
+
+// Largest constant index the three byte operand of a long instruction holds.
+pub const MAX_LONG_CONSTANT: usize = (1 << 24) - 1;
+
+// Bumped whenever opcodes or the bin format change, so compile caches
+// from older builds are ignored.
+pub const BYTECODE_VERSION: u32 = 3;
+
+// First byte of a bin file, tells its format. The first two mark the older
+// format and whether every byte is followed by its line.
+pub const BIN_DEBUG_INFO: u8 = 0xff;
+pub const BIN_STRIPPED: u8 = 0xfe;
+// Bins written by `Chunk::to_bin`, with the constant pool and debug info
+// stored apart from the code.
+pub const BIN_CONSTANT_POOL: u8 = 0xfd;
+
+// Tags of the constants in a bin's pool.
+const BIN_NIL: u8 = 0;
+const BIN_FALSE: u8 = 1;
+const BIN_TRUE: u8 = 2;
+const BIN_NUMBER: u8 = 3;
+const BIN_STRING: u8 = 4;
+const BIN_FUNCTION: u8 = 5;
+
+fn write_bin_len(bin: &mut Vec<u8>, len: usize) -> Result<(), String> {
+    match u32::try_from(len) {
+        Ok(len) => bin.extend(len.to_be_bytes()),
+        Err(_) => {
+            return Err(dissasemble_error(format!(
+                "{} is too large for a bin.",
+                len
+            )))
+        }
+    }
+    return Ok(());
+}
+
+fn write_bin_string(bin: &mut Vec<u8>, chars: &str) -> Result<(), String> {
+    write_bin_len(bin, chars.len())?;
+    bin.extend(chars.as_bytes());
+    return Ok(());
+}
+
+// Cursor over a bin written by `Chunk::to_bin`.
+struct BinReader<'a> {
+    bin: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> BinReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        match self.bin.get(self.offset..self.offset.saturating_add(len)) {
+            Some(bytes) => {
+                self.offset += len;
+                return Ok(bytes);
+            }
+            None => {
+                return Err(dissasemble_error(format!(
+                    "Unexpected end of bin at {}.",
+                    self.offset
+                )))
+            }
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        return Ok(self.bytes(1)?[0]);
+    }
+
+    fn word(&mut self) -> Result<[u8; 4], String> {
+        let mut word = [0; 4];
+        word.copy_from_slice(self.bytes(4)?);
+        return Ok(word);
+    }
+
+    fn usize(&mut self) -> Result<usize, String> {
+        return Ok(u32::from_be_bytes(self.word()?) as usize);
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.usize()?;
+        match std::str::from_utf8(self.bytes(len)?) {
+            Ok(chars) => return Ok(chars.to_string()),
+            Err(_) => return Err(dissasemble_error("Invalid UTF-8 in bin.".to_string())),
+        }
+    }
+
+    fn positions(&mut self) -> Result<Vec<i32>, String> {
+        let mut positions = vec![];
+        for _ in 0..self.usize()? {
+            positions.push(i32::from_be_bytes(self.word()?));
+        }
+        return Ok(positions);
+    }
+
+    fn chunk(&mut self) -> Result<Chunk, String> {
+        let len = self.usize()?;
+        let code = self.bytes(len)?.to_vec();
+        let lines = self.positions()?;
+        let columns = self.positions()?;
+
+        let mut constants = vec![];
+        for _ in 0..self.usize()? {
+            constants.push(self.constant()?);
+        }
+        return Ok(Chunk {
+            code,
+            constants,
+            lines,
+            columns,
+        });
+    }
+
+    fn constant(&mut self) -> Result<Value, String> {
+        match self.byte()? {
+            BIN_NIL => return Ok(Value::NIL),
+            BIN_FALSE => return Ok(Value::FALSE),
+            BIN_TRUE => return Ok(Value::TRUE),
+            BIN_NUMBER => {
+                let mut bits = [0; 8];
+                bits.copy_from_slice(self.bytes(8)?);
+                return Ok(Value::from_number(f64::from_be_bytes(bits)));
+            }
+            BIN_STRING => return Ok(Value::from_string(self.string()?)),
+            BIN_FUNCTION => {
+                let [arity, min_arity, upvalue_count] = [self.byte()?, self.byte()?, self.byte()?];
+                let mut entry_points = vec![];
+                for _ in 0..self.usize()? {
+                    entry_points.push(self.usize()?);
+                }
+                let name = match self.byte()? {
+                    0 => None,
+                    _ => Some(self.string()?),
+                };
+                let function = ObjFunction {
+                    arity,
+                    min_arity,
+                    entry_points,
+                    upvalue_count,
+                    chunk: Rc::new(self.chunk()?),
+                    name,
+                };
+                return Ok(Value::from_obj(Rc::new(Obj::ObjFunction(function))));
+            }
+            tag => {
+                return Err(dissasemble_error(format!(
+                    "Unknown constant tag {} at {} in bin.",
+                    tag,
+                    self.offset - 1
+                )))
+            }
+        }
+    }
+}
+
+// Why `Chunk::verify_integrity` rejected a chunk. Offsets are where the
+// offending instruction starts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChunkError {
+    // Lines or columns don't have one entry per byte of code.
+    DebugInfoMismatch { code: usize, debug_info: usize },
+    InvalidInstruction(usize, u8),
+    MissingOperands(usize),
+    ConstantOutOfRange(usize, usize),
+    InvalidTypeTag(usize, u8),
+    LoopBeforeStart(usize),
+    InvalidJumpTarget(usize),
+    // Function name and the entry point that isn't an instruction.
+    InvalidEntryPoint(String, usize),
+    MissingReturn,
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkError::DebugInfoMismatch { code, debug_info } => {
+                return write!(f, "Debug info covers {} of {} bytes.", debug_info, code)
+            }
+            ChunkError::InvalidInstruction(offset, byte) => {
+                return write!(f, "Byte {} at {} isn't an instruction.", byte, offset)
+            }
+            ChunkError::MissingOperands(offset) => {
+                return write!(f, "Instruction at {} is missing its operands.", offset)
+            }
+            ChunkError::ConstantOutOfRange(offset, constant) => {
+                return write!(f, "Constant {} at {} doesn't exist.", constant, offset)
+            }
+            ChunkError::InvalidTypeTag(offset, tag) => {
+                return write!(f, "Type tag {} at {} doesn't exist.", tag, offset)
+            }
+            ChunkError::LoopBeforeStart(offset) => {
+                return write!(f, "Loop at {} jumps before the chunk.", offset)
+            }
+            ChunkError::InvalidJumpTarget(target) => {
+                return write!(f, "Jump target {} isn't an instruction.", target)
+            }
+            ChunkError::InvalidEntryPoint(function, entry_point) => {
+                return write!(
+                    f,
+                    "Entry point {} of {} isn't an instruction.",
+                    entry_point, function
+                )
+            }
+            ChunkError::MissingReturn => return write!(f, "Chunk doesn't end with a return."),
+        }
+    }
+}
+
+impl std::error::Error for ChunkError {}
+
+// One difference found by `Chunk::diff`. Removals and changes are at their
+// offset or index in the original chunk, additions at theirs in the new one.
 #[derive(Debug, Clone)]
+pub enum ChunkDiff {
+    Added(usize, u8),
+    Removed(usize, u8),
+    Changed(usize, u8, u8),
+    ConstantAdded(usize, Value),
+    ConstantRemoved(usize, Value),
+    ConstantChanged(usize, Value, Value),
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Chunk {
-    pub code: Vec<u8>,
+    code: Vec<u8>,
     pub constants: Vec<Value>,
-    pub lines: Vec<i32>,
+    lines: Vec<i32>,
+    // Column of the token each byte was emitted for, 0 when unknown.
+    pub columns: Vec<i32>,
 }
 
 impl Chunk {
@@ -58,87 +403,924 @@ impl Chunk {
             code: vec![],
             constants: vec![],
             lines: vec![],
+            columns: vec![],
+        }
+    }
+
+    // Bare code with no constants or line info.
+    pub fn from_code(code: Vec<u8>) -> Self {
+        Self {
+            code,
+            constants: vec![],
+            lines: vec![],
+            columns: vec![],
+        }
+    }
+
+    pub fn code_len(&self) -> usize {
+        return self.code.len();
+    }
+
+    // `None` at or past the end of the code.
+    pub fn byte_at(&self, offset: usize) -> Option<u8> {
+        return self.code.get(offset).copied();
+    }
+
+    // Safety: `offset` has to be less than `code_len`.
+    #[cfg(feature = "unchecked")]
+    pub(crate) unsafe fn byte_at_unchecked(&self, offset: usize) -> u8 {
+        return *self.code.get_unchecked(offset);
+    }
+
+    // Overwrites a byte already written, like a jump's placeholder offset.
+    // Offsets past the end are ignored.
+    pub fn patch_byte(&mut self, offset: usize, byte: u8) {
+        if let Some(slot) = self.code.get_mut(offset) {
+            *slot = byte;
         }
     }
 
     pub fn write_instruction(&mut self, instruction: OpCode, line: i32) {
-        self.lines.push(line);
-        self.code.push(instruction as u8);
+        self.write_byte(instruction as u8, line);
     }
 
     pub fn write_byte(&mut self, byte: u8, line: i32) {
+        self.write_byte_at(byte, line, 0);
+    }
+
+    pub fn write_byte_at(&mut self, byte: u8, line: i32, column: i32) {
         self.lines.push(line);
+        self.columns.push(column);
         self.code.push(byte);
     }
 
-    pub fn add_constant(&mut self, constant: Value) -> u8 {
+    // Line of the byte at `offset`, 0 when the chunk has no debug info.
+    pub fn line_at(&self, offset: usize) -> i32 {
+        return self.lines.get(offset).copied().unwrap_or(0);
+    }
+
+    // Source position of the byte at `offset` as (line, column).
+    pub fn position(&self, offset: usize) -> (i32, i32) {
+        let column = self.columns.get(offset).copied().unwrap_or(0);
+        return (self.line_at(offset), column);
+    }
+
+    // Offsets of the instructions that start on `line`, the reverse of
+    // `line_at`. Operand bytes are left out since `ip` never stops on them.
+    pub fn map_line_to_instructions(&self, line: i32) -> Vec<usize> {
+        return self
+            .instructions()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(offset, _)| offset)
+            .filter(|offset| self.line_at(*offset) == line)
+            .collect();
+    }
+
+    pub fn clone_without_debug_info(&self) -> Chunk {
+        return Chunk {
+            code: self.code.clone(),
+            constants: self.constants.clone(),
+            lines: vec![],
+            columns: vec![],
+        };
+    }
+
+    // Decodes a bin file. Older bins inline byte sized numbers in place of
+    // a constant pool, those without a flag byte also predate the flag and
+    // always carry line numbers.
+    pub fn from_bin(bin: &[u8]) -> Result<Chunk, String> {
+        if bin.first() == Some(&BIN_CONSTANT_POOL) {
+            let mut reader = BinReader { bin, offset: 1 };
+            let chunk = reader.chunk()?;
+            if reader.offset != bin.len() {
+                return Err(dissasemble_error(format!(
+                    "Unexpected trailing bytes at {} in bin.",
+                    reader.offset
+                )));
+            }
+            return Ok(chunk);
+        }
+
+        let (bytes, debug_info) = match bin.first() {
+            Some(&BIN_DEBUG_INFO) => (&bin[1..], true),
+            Some(&BIN_STRIPPED) => (&bin[1..], false),
+            _ => (bin, true),
+        };
+
+        let mut lines: Vec<i32> = vec![];
+        let mut instructions: Vec<u8> = vec![];
+        if debug_info {
+            for pair in bytes.chunks_exact(2) {
+                instructions.push(pair[0]);
+                lines.push(pair[1].into());
+            }
+        } else {
+            instructions.extend_from_slice(bytes);
+        }
+
+        let mut chunk = Chunk::new();
+        let line = |i: usize| lines.get(i).copied().unwrap_or(0);
+
+        let mut i = 0;
+        loop {
+            if i == instructions.len() {
+                break;
+            }
+
+            let current = instructions[i];
+            match byte_to_op(current) {
+                Ok(OpCode::OpConstant) => {
+                    if let Some(next) = instructions.get(i + 1) {
+                        // Bins only hold byte sized numbers, shared so the
+                        // pool never outgrows a one byte index
+                        let value = f64::from(*next);
+                        let constant = match chunk
+                            .constants
+                            .iter()
+                            .position(|constant| constant.as_number() == value)
+                        {
+                            Some(constant) => constant,
+                            None => chunk.push_constant(Value::from_number(value)),
+                        };
+                        chunk.write_instruction(OpCode::OpConstant, line(i));
+                        chunk.write_byte(constant as u8, line(i + 1));
+                        i += 1;
+                    }
+                }
+                Ok(OpCode::OpConstantLong) if i + 3 < instructions.len() => {
+                    // The number is inlined in all three operand bytes
+                    let value = ((instructions[i + 1] as u32) << 16
+                        | (instructions[i + 2] as u32) << 8
+                        | instructions[i + 3] as u32) as f64;
+                    let constant = match chunk
+                        .constants
+                        .iter()
+                        .position(|constant| constant.as_number() == value)
+                    {
+                        Some(constant) => constant,
+                        None => chunk.push_constant(Value::from_number(value)),
+                    };
+                    chunk.write_instruction(OpCode::OpConstantLong, line(i));
+                    for (j, byte) in (constant as u32).to_be_bytes()[1..].iter().enumerate() {
+                        chunk.write_byte(*byte, line(i + 1 + j));
+                    }
+                    i += 3;
+                }
+                Ok(instruction) => {
+                    chunk.write_byte(current, line(i));
+                    for _ in 0..operand_len(&instruction) {
+                        if let Some(operand) = instructions.get(i + 1) {
+                            chunk.write_byte(*operand, line(i + 1));
+                            i += 1;
+                        }
+                    }
+                }
+                Err(_) => chunk.write_byte(current, line(i)),
+            }
+
+            i += 1;
+        }
+
+        if !debug_info {
+            return Ok(chunk.clone_without_debug_info());
+        }
+        return Ok(chunk);
+    }
+
+    // Encodes the chunk as a bin file. The constant pool is stored whole, so
+    // strings and functions, nested chunks included, survive the round trip.
+    pub fn to_bin(&self) -> Result<Vec<u8>, String> {
+        let mut bin = vec![BIN_CONSTANT_POOL];
+        self.write_bin(&mut bin)?;
+        return Ok(bin);
+    }
+
+    // Lengths are big endian u32s: the code, the lines and columns (empty
+    // when stripped) and the constants, each behind its tag.
+    fn write_bin(&self, bin: &mut Vec<u8>) -> Result<(), String> {
+        write_bin_len(bin, self.code.len())?;
+        bin.extend(&self.code);
+        for positions in [&self.lines, &self.columns] {
+            write_bin_len(bin, positions.len())?;
+            for position in positions {
+                bin.extend(position.to_be_bytes());
+            }
+        }
+
+        write_bin_len(bin, self.constants.len())?;
+        for constant in &self.constants {
+            match constant {
+                Value::ValNil => bin.push(BIN_NIL),
+                Value::ValBool(false) => bin.push(BIN_FALSE),
+                Value::ValBool(true) => bin.push(BIN_TRUE),
+                Value::ValNumber(number) => {
+                    bin.push(BIN_NUMBER);
+                    bin.extend(number.to_be_bytes());
+                }
+                Value::ValObj(obj) => match &**obj {
+                    Obj::ObjString(chars) => {
+                        bin.push(BIN_STRING);
+                        write_bin_string(bin, chars)?;
+                    }
+                    Obj::ObjFunction(function) => {
+                        bin.push(BIN_FUNCTION);
+                        bin.extend([function.arity, function.min_arity, function.upvalue_count]);
+                        write_bin_len(bin, function.entry_points.len())?;
+                        for entry_point in &function.entry_points {
+                            write_bin_len(bin, *entry_point)?;
+                        }
+                        match &function.name {
+                            Some(name) => {
+                                bin.push(1);
+                                write_bin_string(bin, name)?;
+                            }
+                            None => bin.push(0),
+                        }
+                        function.chunk.write_bin(bin)?;
+                    }
+                    _ => {
+                        return Err(dissasemble_error(format!(
+                            "Can't store a {} constant in a bin.",
+                            obj.type_name()
+                        )))
+                    }
+                },
+            }
+        }
+        return Ok(());
+    }
+
+    // Adds `value` and the instruction loading it, the long form once the
+    // pool outgrows a one byte index. Returns the constant's index.
+    pub fn write_constant_long(&mut self, value: Value, line: i32) -> Result<u32, String> {
+        return self.write_constant_long_at(value, line, 0);
+    }
+
+    pub fn write_constant_long_at(
+        &mut self,
+        value: Value,
+        line: i32,
+        column: i32,
+    ) -> Result<u32, String> {
+        if self.constants.len() > MAX_LONG_CONSTANT {
+            return Err("Too many constants in one chunk.".to_string());
+        }
+        let constant = self.push_constant(value) as u32;
+
+        if constant <= u8::MAX as u32 {
+            self.write_byte_at(OpCode::OpConstant as u8, line, column);
+            self.write_byte_at(constant as u8, line, column);
+        } else {
+            self.write_byte_at(OpCode::OpConstantLong as u8, line, column);
+            for byte in &constant.to_be_bytes()[1..] {
+                self.write_byte_at(*byte, line, column);
+            }
+        }
+        return Ok(constant);
+    }
+
+    // Instructions with a one byte operand can only refer to the first 256
+    // constants, callers check the index fits.
+    #[deprecated(note = "use `write_constant_long`, it picks the load instruction the index needs")]
+    pub fn add_constant(&mut self, constant: Value) -> usize {
+        return self.push_constant(constant);
+    }
+
+    // Loads go through `write_constant_long`, this is for constants other
+    // instructions refer to, like global names.
+    pub(crate) fn push_constant(&mut self, constant: Value) -> usize {
         self.constants.push(constant);
-        return self.constants.len() as u8 - 1;
+        return self.constants.len() - 1;
     }
 
-    pub fn dissasemble(&self, name: &str) -> Result<(), String> {
-        println!("== {} ==", name);
+    // Appends `other` so execution falls through from this chunk into it.
+    // Jumps are relative and stay valid, constant operands are rebased onto
+    // the combined constant pool.
+    pub fn merge(&mut self, other: Chunk) -> Result<(), String> {
+        let base = self.constants.len();
+        for (offset, instruction) in other.instructions()? {
+            let constant = match instruction {
+                OpCode::OpConstant
+                | OpCode::OpDefineGlobal
+                | OpCode::OpGetGlobal
+                | OpCode::OpSetGlobal => other.code[offset + 1] as usize + base,
+                OpCode::OpConstantLong
+                | OpCode::OpDefineGlobalLong
+                | OpCode::OpGetGlobalLong
+                | OpCode::OpSetGlobalLong => other.read_long(offset + 1) + base,
+                _ => continue,
+            };
+            if (operand_len(&instruction) == 1 && constant > u8::MAX as usize)
+                || constant > MAX_LONG_CONSTANT
+            {
+                return Err(dissasemble_error(
+                    "Too many constants in merged chunk.".to_string(),
+                ));
+            }
+        }
+
+        // Drops the trailing return, jumps to it now land on `other`
+        if let Some(last) = self.last_instruction() {
+            if matches!(byte_to_op(self.code[last]), Ok(OpCode::OpReturn)) {
+                self.code.truncate(last);
+            }
+        }
+
+        let start = self.code.len();
+        let has_lines = !self.lines.is_empty() || !other.lines.is_empty();
+
+        self.lines.resize(start, 0);
+        self.columns.resize(start, 0);
+        self.code.extend(other.code);
+        self.lines.extend(other.lines);
+        self.columns.extend(other.columns);
+        self.lines.resize(self.code.len(), 0);
+        self.columns.resize(self.code.len(), 0);
+        if !has_lines {
+            self.lines.clear();
+            self.columns.clear();
+        }
+
+        let mut offset = start;
+        while offset < self.code.len() {
+            let instruction = byte_to_op(self.code[offset])?;
+            match instruction {
+                OpCode::OpConstant
+                | OpCode::OpDefineGlobal
+                | OpCode::OpGetGlobal
+                | OpCode::OpSetGlobal => {
+                    if let Some(constant) = self.code.get_mut(offset + 1) {
+                        *constant += base as u8;
+                    }
+                }
+                OpCode::OpConstantLong
+                | OpCode::OpDefineGlobalLong
+                | OpCode::OpGetGlobalLong
+                | OpCode::OpSetGlobalLong => {
+                    let constant = self.read_long(offset + 1) + base;
+                    self.code[offset + 1..offset + 4]
+                        .copy_from_slice(&(constant as u32).to_be_bytes()[1..]);
+                }
+                _ => (),
+            }
+            offset += 1 + operand_len(&instruction);
+        }
+
+        self.constants.extend(other.constants);
+        return Ok(());
+    }
 
+    fn last_instruction(&self) -> Option<usize> {
+        let mut last = None;
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = self.dissasemble_instruction(offset)?;
+            last = Some(offset);
+            match byte_to_op(self.code[offset]) {
+                Ok(instruction) => offset += 1 + operand_len(&instruction),
+                Err(_) => return None,
+            }
+        }
+        return last;
+    }
+
+    // Offset and opcode of every instruction, fails on bytes that don't
+    // decode or operands running past the end of the code.
+    pub fn instructions(&self) -> Result<Vec<(usize, OpCode)>, String> {
+        let mut instructions = vec![];
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let instruction = byte_to_op(self.code[offset])?;
+            let next = offset + 1 + operand_len(&instruction);
+            if next > self.code.len() {
+                return Err(dissasemble_error(format!(
+                    "Missing operand for {:?} at {}.",
+                    instruction, offset
+                )));
+            }
+            instructions.push((offset, instruction));
+            offset = next;
+        }
+        return Ok(instructions);
+    }
+
+    // JSON mapping every instruction offset to its source position and
+    // every constant index to its printed value, for external debuggers.
+    pub fn to_source_map(&self) -> Result<String, String> {
+        let instructions: Vec<String> = self
+            .instructions()?
+            .iter()
+            .map(|(offset, _)| {
+                let (line, column) = self.position(*offset);
+                format!(
+                    "{{\"offset\":{},\"line\":{},\"column\":{}}}",
+                    offset, line, column
+                )
+            })
+            .collect();
+
+        return Ok(format!(
+            "{{\"instructions\":[{}],\"constants\":{}}}",
+            instructions.join(","),
+            self.constants_json()
+        ));
+    }
+
+    // Disassembly as JSON, one object per instruction with its raw operand
+    // bytes, followed by the constant pool.
+    pub fn to_json(&self) -> Result<String, String> {
+        let instructions: Vec<String> = self
+            .instructions()?
+            .iter()
+            .map(|(offset, instruction)| {
+                let operands: Vec<String> = self.code
+                    [offset + 1..offset + 1 + operand_len(instruction)]
+                    .iter()
+                    .map(|operand| operand.to_string())
+                    .collect();
+                format!(
+                    "{{\"offset\":{},\"opcode\":\"{:?}\",\"operands\":[{}],\"line\":{}}}",
+                    offset,
+                    instruction,
+                    operands.join(","),
+                    self.line_at(*offset)
+                )
+            })
+            .collect();
+
+        return Ok(format!(
+            "{{\"instructions\":[{}],\"constants\":{}}}",
+            instructions.join(","),
+            self.constants_json()
+        ));
+    }
+
+    fn constants_json(&self) -> String {
+        let format = ValueFormat::default();
+        let constants: Vec<String> = self
+            .constants
+            .iter()
+            .enumerate()
+            .map(|(index, constant)| {
+                format!(
+                    "{{\"index\":{},\"type\":\"{}\",\"value\":\"{}\"}}",
+                    index,
+                    constant.type_name(),
+                    escape_json(&constant.display_with_format(&format))
+                )
+            })
+            .collect();
+        return format!("[{}]", constants.join(","));
+    }
+
+    // Checks that every instruction decodes with all of its operands, that
+    // constant indices and jump targets stay inside the chunk and that it
+    // ends with a return, so running it can never read past its end.
+    pub fn verify(&self) -> Result<(), String> {
+        return self
+            .verify_integrity()
+            .map_err(|error| dissasemble_error(error.to_string()));
+    }
+
+    // `verify` with the failure kept apart, plus a check that line and column
+    // info, when present, covers every byte.
+    pub fn verify_integrity(&self) -> Result<(), ChunkError> {
+        for debug_info in [self.lines.len(), self.columns.len()] {
+            if debug_info != 0 && debug_info != self.code.len() {
+                return Err(ChunkError::DebugInfoMismatch {
+                    code: self.code.len(),
+                    debug_info,
+                });
+            }
         }
 
+        let mut boundaries = vec![false; self.code.len()];
+        let mut targets: Vec<usize> = vec![];
+        let mut last = None;
+
+        let mut offset = 0;
+        while offset < self.code.len() {
+            boundaries[offset] = true;
+            let instruction = match byte_to_op(self.code[offset]) {
+                Ok(instruction) => instruction,
+                Err(_) => return Err(ChunkError::InvalidInstruction(offset, self.code[offset])),
+            };
+            let len = operand_len(&instruction);
+            if offset + len >= self.code.len() {
+                return Err(ChunkError::MissingOperands(offset));
+            }
+
+            match instruction {
+                OpCode::OpConstant
+                | OpCode::OpDefineGlobal
+                | OpCode::OpGetGlobal
+                | OpCode::OpSetGlobal
+                | OpCode::OpConstantLong
+                | OpCode::OpDefineGlobalLong
+                | OpCode::OpGetGlobalLong
+                | OpCode::OpSetGlobalLong => {
+                    let constant = match operand_len(&instruction) {
+                        3 => self.read_long(offset + 1),
+                        _ => self.code[offset + 1] as usize,
+                    };
+                    if constant >= self.constants.len() {
+                        return Err(ChunkError::ConstantOutOfRange(offset, constant));
+                    }
+                }
+                OpCode::OpJump | OpCode::OpJumpIfNil | OpCode::OpJumpIfFalse => {
+                    targets.push(offset + 3 + self.read_short(offset + 1));
+                }
+                OpCode::OpAssertType if ValueType::from_tag(self.code[offset + 1]).is_none() => {
+                    return Err(ChunkError::InvalidTypeTag(offset, self.code[offset + 1]));
+                }
+                OpCode::OpLoop => match (offset + 3).checked_sub(self.read_short(offset + 1)) {
+                    Some(target) => targets.push(target),
+                    None => return Err(ChunkError::LoopBeforeStart(offset)),
+                },
+                _ => (),
+            }
+
+            last = Some(instruction);
+            offset += 1 + len;
+        }
+
+        if !matches!(last, Some(OpCode::OpReturn)) {
+            return Err(ChunkError::MissingReturn);
+        }
+
+        for constant in &self.constants {
+            if let Some(Obj::ObjFunction(function)) = constant.as_obj().map(|obj| &**obj) {
+                function.chunk.verify_integrity()?;
+                for entry_point in &function.entry_points {
+                    if !function.chunk.is_boundary(*entry_point) {
+                        return Err(ChunkError::InvalidEntryPoint(
+                            function.display_name().to_string(),
+                            *entry_point,
+                        ));
+                    }
+                }
+            }
+        }
+
+        for target in targets {
+            if !boundaries.get(target).copied().unwrap_or(false) {
+                return Err(ChunkError::InvalidJumpTarget(target));
+            }
+        }
+
+        return Ok(());
+    }
+
+    // Marks the offset of every instruction some path from offset 0 can
+    // reach. Operand bytes, undecodable code and dead code stay `false`.
+    pub fn reachability_analysis(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.code.len()];
+        let mut pending = vec![0];
+
+        while let Some(offset) = pending.pop() {
+            if offset >= self.code.len() || reachable[offset] {
+                continue;
+            }
+            let instruction = match byte_to_op(self.code[offset]) {
+                Ok(instruction) => instruction,
+                Err(_) => continue,
+            };
+            let next = offset + 1 + operand_len(&instruction);
+            if next > self.code.len() {
+                continue;
+            }
+            reachable[offset] = true;
+
+            match instruction {
+                OpCode::OpReturn => (),
+                OpCode::OpJump => pending.push(next + self.read_short(offset + 1)),
+                OpCode::OpJumpIfNil | OpCode::OpJumpIfFalse => {
+                    pending.push(next + self.read_short(offset + 1));
+                    pending.push(next);
+                }
+                OpCode::OpLoop => {
+                    if let Some(target) = next.checked_sub(self.read_short(offset + 1)) {
+                        pending.push(target);
+                    }
+                }
+                _ => pending.push(next),
+            }
+        }
+
+        return reachable;
+    }
+
+    // Edit script turning `self` into `other`, for checking what an
+    // optimization pass did. Code is diffed through its longest common
+    // subsequence, constants by index.
+    pub fn diff(&self, other: &Chunk) -> Vec<ChunkDiff> {
+        let (old, new) = (&self.code, &other.code);
+        // common[i][j] is the length of the longest common subsequence of
+        // old[i..] and new[j..].
+        let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+        for i in (0..old.len()).rev() {
+            for j in (0..new.len()).rev() {
+                common[i][j] = if old[i] == new[j] {
+                    common[i + 1][j + 1] + 1
+                } else {
+                    common[i + 1][j].max(common[i][j + 1])
+                };
+            }
+        }
+
+        let mut diffs = vec![];
+        let (mut i, mut j) = (0, 0);
+        while i < old.len() && j < new.len() {
+            if old[i] == new[j] {
+                i += 1;
+                j += 1;
+            } else if common[i + 1][j + 1] == common[i][j] {
+                diffs.push(ChunkDiff::Changed(i, old[i], new[j]));
+                i += 1;
+                j += 1;
+            } else if common[i + 1][j] >= common[i][j + 1] {
+                diffs.push(ChunkDiff::Removed(i, old[i]));
+                i += 1;
+            } else {
+                diffs.push(ChunkDiff::Added(j, new[j]));
+                j += 1;
+            }
+        }
+        diffs.extend((i..old.len()).map(|i| ChunkDiff::Removed(i, old[i])));
+        diffs.extend((j..new.len()).map(|j| ChunkDiff::Added(j, new[j])));
+
+        let same = |a: &Value, b: &Value| {
+            return a.type_name() == b.type_name()
+                && a.to_canonical_string() == b.to_canonical_string();
+        };
+        for index in 0..self.constants.len().max(other.constants.len()) {
+            match (self.constants.get(index), other.constants.get(index)) {
+                (Some(a), Some(b)) if !same(a, b) => {
+                    diffs.push(ChunkDiff::ConstantChanged(index, a.clone(), b.clone()));
+                }
+                (Some(a), None) => diffs.push(ChunkDiff::ConstantRemoved(index, a.clone())),
+                (None, Some(b)) => diffs.push(ChunkDiff::ConstantAdded(index, b.clone())),
+                _ => (),
+            }
+        }
+
+        return diffs;
+    }
+
+    fn is_boundary(&self, target: usize) -> bool {
+        let mut offset = 0;
+        while offset < target {
+            match byte_to_op(self.code[offset]) {
+                Ok(instruction) => offset += 1 + operand_len(&instruction),
+                Err(_) => return false,
+            }
+        }
+        return offset == target && target < self.code.len();
+    }
+
+    fn read_short(&self, offset: usize) -> usize {
+        return (self.code[offset] as usize) << 8 | self.code[offset + 1] as usize;
+    }
+
+    // Three byte big endian operand of the long instructions.
+    pub fn read_long(&self, offset: usize) -> usize {
+        return (self.code[offset] as usize) << 16
+            | (self.code[offset + 1] as usize) << 8
+            | self.code[offset + 2] as usize;
+    }
+
+    // One `[index] type: value` line per constant, strings are quoted.
+    pub fn pretty_print_constants(&self) -> String {
+        let format = ValueFormat::default();
+        let mut lines: Vec<String> = vec![];
+        for (index, constant) in self.constants.iter().enumerate() {
+            if constant.is_nil() {
+                lines.push(format!("[{}] nil", index));
+            } else if constant.is_string() {
+                lines.push(format!(
+                    "[{}] string: \"{}\"",
+                    index,
+                    constant.display_with_format(&format)
+                ));
+            } else {
+                lines.push(format!(
+                    "[{}] {}: {}",
+                    index,
+                    constant.type_name(),
+                    constant.display_with_format(&format)
+                ));
+            }
+        }
+        return lines.join("\n");
+    }
+
+    pub fn dissasemble(&self, name: &str) -> Result<(), String> {
+        print!("{}", self.dissasemble_to_string(name)?);
         Ok(())
     }
 
+    // Constant pool of the chunk followed by its listing. Functions in the
+    // pool are listed right after the instruction loading them, indented one
+    // level deeper.
+    pub fn dissasemble_to_string(&self, name: &str) -> Result<String, String> {
+        return self.dissasemble_nested(name, 0);
+    }
+
+    fn dissasemble_nested(&self, name: &str, depth: usize) -> Result<String, String> {
+        let indent = "    ".repeat(depth);
+        let mut out = format!("{}== {} ==\n", indent, name);
+
+        // The whole pool in index order ahead of the instructions referring
+        // into it
+        if !self.constants.is_empty() {
+            out.push_str(&format!("{}== constants ==\n", indent));
+            for line in self.pretty_print_constants().lines() {
+                out.push_str(&format!("{}{}\n", indent, line));
+            }
+        }
+
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let mut line = String::new();
+            let next = self.format_instruction(&mut line, offset)?;
+            out.push_str(&indent);
+            out.push_str(&line);
+
+            let constant = match byte_to_op(self.code[offset]) {
+                Ok(OpCode::OpConstant) => Some(self.code[offset + 1] as usize),
+                Ok(OpCode::OpConstantLong) => Some(self.read_long(offset + 1)),
+                _ => None,
+            };
+            if let Some(constant) = constant {
+                let constant = self.constants.get(constant);
+                if let Some(Obj::ObjFunction(function)) = constant
+                    .and_then(|constant| constant.as_obj())
+                    .map(|obj| &**obj)
+                {
+                    out.push_str(
+                        &function
+                            .chunk
+                            .dissasemble_nested(function.display_name(), depth + 1)?,
+                    );
+                }
+            }
+            offset = next;
+        }
+
+        return Ok(out);
+    }
+
     pub fn dissasemble_instruction(&self, offset: usize) -> Result<usize, String> {
-        print!("{:04} ", offset);
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            print!("   | ");
+        let mut out = String::new();
+        let next = self.format_instruction(&mut out, offset)?;
+        print!("{}", out);
+        return Ok(next);
+    }
+
+    fn format_instruction(&self, out: &mut String, offset: usize) -> Result<usize, String> {
+        out.push_str(&format!("{:04} ", offset));
+        if offset > 0 && self.line_at(offset) == self.line_at(offset - 1) {
+            out.push_str("   | ");
         } else {
-            print!("{:4} ", self.lines[offset]);
+            out.push_str(&format!("{:4} ", self.line_at(offset)));
         }
 
         if let Some(byte) = self.code.get(offset) {
             let instruction = byte_to_op(*byte)?;
+            if offset + operand_len(&instruction) >= self.code.len() {
+                return Err(dissasemble_error(format!(
+                    "Missing operands at offset {}.",
+                    offset
+                )));
+            }
 
             match instruction {
                 OpCode::OpReturn => {
-                    return Ok(self.simple_instruction("OP_RETURN", offset));
+                    return Ok(self.simple_instruction(out, "OP_RETURN", offset));
                 }
                 OpCode::OpConstant => {
-                    return Ok(self.constant_instruction("OP_CONSTANT", offset));
+                    return Ok(self.constant_instruction(out, "OP_CONSTANT", offset));
+                }
+                OpCode::OpConstantLong => {
+                    return Ok(self.constant_long_instruction(out, "OP_CONSTANT_LONG", offset));
+                }
+                OpCode::OpAssertType => {
+                    let tag = self.code[offset + 1];
+                    let name = ValueType::from_tag(tag).map_or("unknown", |tag| tag.name());
+                    out.push_str(&format!("{:16} {:4} ({})\n", "OP_ASSERT_TYPE", tag, name));
+                    return Ok(offset + 2);
                 }
                 OpCode::OpNegate => {
-                    return Ok(self.simple_instruction("OP_NEGATE", offset));
+                    return Ok(self.simple_instruction(out, "OP_NEGATE", offset));
                 }
                 OpCode::OpAdd => {
-                    return Ok(self.simple_instruction("OP_ADD", offset));
+                    return Ok(self.simple_instruction(out, "OP_ADD", offset));
                 }
                 OpCode::OpSubtract => {
-                    return Ok(self.simple_instruction("OP_SUBTRACT", offset));
+                    return Ok(self.simple_instruction(out, "OP_SUBTRACT", offset));
                 }
                 OpCode::OpMultiply => {
-                    return Ok(self.simple_instruction("OP_MULTIPLY", offset));
+                    return Ok(self.simple_instruction(out, "OP_MULTIPLY", offset));
                 }
                 OpCode::OpDivide => {
-                    return Ok(self.simple_instruction("OP_DIVIDE", offset));
+                    return Ok(self.simple_instruction(out, "OP_DIVIDE", offset));
+                }
+                OpCode::OpModulo => {
+                    return Ok(self.simple_instruction(out, "OP_MODULO", offset));
+                }
+                OpCode::OpShiftLeft => {
+                    return Ok(self.simple_instruction(out, "OP_SHIFT_LEFT", offset));
+                }
+                OpCode::OpShiftRight => {
+                    return Ok(self.simple_instruction(out, "OP_SHIFT_RIGHT", offset));
+                }
+                OpCode::OpShiftRightLogical => {
+                    return Ok(self.simple_instruction(out, "OP_SHIFT_RIGHT_LOGICAL", offset));
+                }
+                OpCode::OpRange => {
+                    return Ok(self.simple_instruction(out, "OP_RANGE", offset));
+                }
+                OpCode::OpRangeInclusive => {
+                    return Ok(self.simple_instruction(out, "OP_RANGE_INCLUSIVE", offset));
+                }
+                OpCode::OpPop => return Ok(self.simple_instruction(out, "OP_POP", offset)),
+                OpCode::OpJump => return Ok(self.jump_instruction(out, "OP_JUMP", 1, offset)),
+                OpCode::OpJumpIfNil => {
+                    return Ok(self.jump_instruction(out, "OP_JUMP_IF_NIL", 1, offset));
+                }
+                OpCode::OpJumpIfFalse => {
+                    return Ok(self.jump_instruction(out, "OP_JUMP_IF_FALSE", 1, offset));
+                }
+                OpCode::OpLoop => return Ok(self.jump_instruction(out, "OP_LOOP", -1, offset)),
+                OpCode::OpPrint => return Ok(self.simple_instruction(out, "OP_PRINT", offset)),
+                OpCode::OpWrite => return Ok(self.simple_instruction(out, "OP_WRITE", offset)),
+                OpCode::OpPopN => return Ok(self.byte_instruction(out, "OP_POP_N", offset)),
+                OpCode::OpPrintMany => {
+                    return Ok(self.byte_instruction(out, "OP_PRINT_MANY", offset));
+                }
+                OpCode::OpPushFrame => {
+                    return Ok(self.simple_instruction(out, "OP_PUSH_FRAME", offset))
+                }
+                OpCode::OpPopFrame => {
+                    return Ok(self.simple_instruction(out, "OP_POP_FRAME", offset))
+                }
+                OpCode::OpGetLocal => {
+                    return Ok(self.byte_instruction(out, "OP_GET_LOCAL", offset))
+                }
+                OpCode::OpSetLocal => {
+                    return Ok(self.byte_instruction(out, "OP_SET_LOCAL", offset))
+                }
+                OpCode::OpIncrement => {
+                    return Ok(self.byte_instruction(out, "OP_INCREMENT", offset))
+                }
+                OpCode::OpDecrement => {
+                    return Ok(self.byte_instruction(out, "OP_DECREMENT", offset))
+                }
+                OpCode::OpCall => return Ok(self.byte_instruction(out, "OP_CALL", offset)),
+                OpCode::OpCallNative => {
+                    return Ok(self.byte_instruction(out, "OP_CALL_NATIVE", offset));
+                }
+                OpCode::OpTailCall => {
+                    return Ok(self.byte_instruction(out, "OP_TAIL_CALL", offset))
+                }
+                OpCode::OpDefineGlobal => {
+                    return Ok(self.constant_instruction(out, "OP_DEFINE_GLOBAL", offset))
+                }
+                OpCode::OpGetGlobal => {
+                    return Ok(self.constant_instruction(out, "OP_GET_GLOBAL", offset))
+                }
+                OpCode::OpSetGlobal => {
+                    return Ok(self.constant_instruction(out, "OP_SET_GLOBAL", offset))
+                }
+                OpCode::OpDefineGlobalLong => {
+                    return Ok(self.constant_long_instruction(out, "OP_DEFINE_GLOBAL_LONG", offset))
+                }
+                OpCode::OpGetGlobalLong => {
+                    return Ok(self.constant_long_instruction(out, "OP_GET_GLOBAL_LONG", offset))
+                }
+                OpCode::OpSetGlobalLong => {
+                    return Ok(self.constant_long_instruction(out, "OP_SET_GLOBAL_LONG", offset))
                 }
                 OpCode::OpEqual => {
-                    return Ok(self.simple_instruction("OP_EQUAL", offset));
+                    return Ok(self.simple_instruction(out, "OP_EQUAL", offset));
                 }
                 OpCode::OpGreater => {
-                    return Ok(self.simple_instruction("OP_GREATER", offset));
+                    return Ok(self.simple_instruction(out, "OP_GREATER", offset));
                 }
                 OpCode::OpLess => {
-                    return Ok(self.simple_instruction("OP_LESS", offset));
+                    return Ok(self.simple_instruction(out, "OP_LESS", offset));
                 }
-                OpCode::OpNil => return Ok(self.simple_instruction("OP_NIL", offset)),
-                OpCode::OpTrue => return Ok(self.simple_instruction("OP_TRUE", offset)),
-                OpCode::OpFalse => return Ok(self.simple_instruction("OP_FALSE", offset)),
-                OpCode::OpNot => return Ok(self.simple_instruction("OP_NOT", offset)),
-                _ => {
-                    return Err(dissasemble_error(format!(
-                        "Unknown instruction found: '{:?}'\nDissasembling not implemented.",
-                        instruction
-                    )));
+                OpCode::OpContains => {
+                    return Ok(self.simple_instruction(out, "OP_CONTAINS", offset));
                 }
+                OpCode::OpIs => return Ok(self.simple_instruction(out, "OP_IS", offset)),
+                OpCode::OpNil => return Ok(self.simple_instruction(out, "OP_NIL", offset)),
+                OpCode::OpZero => return Ok(self.simple_instruction(out, "OP_ZERO", offset)),
+                OpCode::OpOne => return Ok(self.simple_instruction(out, "OP_ONE", offset)),
+                OpCode::OpTrue => return Ok(self.simple_instruction(out, "OP_TRUE", offset)),
+                OpCode::OpFalse => return Ok(self.simple_instruction(out, "OP_FALSE", offset)),
+                OpCode::OpNot => return Ok(self.simple_instruction(out, "OP_NOT", offset)),
+                OpCode::OpType => return Ok(self.simple_instruction(out, "OP_TYPE", offset)),
             }
         } else {
             return Err(dissasemble_error(format!(
@@ -148,16 +1330,357 @@ impl Chunk {
         }
     }
 
-    fn simple_instruction(&self, name: &str, offset: usize) -> usize {
-        println!("{}", name);
+    fn simple_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        out.push_str(&format!("{}\n", name));
         return offset + 1;
     }
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
-        let constant = self.code[offset + 1];
-        print!("{:16} {:04} '", name, constant);
-        self.constants[constant as usize].print();
-        println!("'");
+    fn byte_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        let operand = self.code[offset + 1];
+        out.push_str(&format!("{:16} {:4}\n", name, operand));
         return offset + 2;
     }
+
+    fn jump_instruction(&self, out: &mut String, name: &str, sign: i32, offset: usize) -> usize {
+        let jump = (self.code[offset + 1] as u16) << 8 | self.code[offset + 2] as u16;
+        let target = offset as i32 + 3 + sign * jump as i32;
+        out.push_str(&format!("{:16} {:4} -> {}\n", name, offset, target));
+        return offset + 3;
+    }
+
+    fn constant_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        let constant = self.code[offset + 1] as usize;
+        out.push_str(&format!(
+            "{:16} {:04} '{}'\n",
+            name,
+            constant,
+            self.constant_display(constant)
+        ));
+        return offset + 2;
+    }
+
+    fn constant_long_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        let constant = self.read_long(offset + 1);
+        out.push_str(&format!(
+            "{:16} {:04} '{}'\n",
+            name,
+            constant,
+            self.constant_display(constant)
+        ));
+        return offset + 4;
+    }
+
+    // Disassembling doesn't require a verified chunk, a missing constant is
+    // shown rather than panicking.
+    fn constant_display(&self, constant: usize) -> String {
+        match self.constants.get(constant) {
+            Some(value) => return value.display_with_format(&ValueFormat::default()),
+            None => return "<missing>".to_string(),
+        }
+    }
+}
+
+// Disassembly of `function` and every function nested inside it.
+pub fn disassemble_function(function: &ObjFunction) -> Result<String, String> {
+    return function
+        .chunk
+        .dissasemble_to_string(function.display_name());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+
+    #[test]
+    fn merged_chunk_runs_on_into_the_second() {
+        use crate::vm::{StepResult, Vm};
+
+        let mut first = Chunk::new();
+        for number in [6.0, 4.0] {
+            let index = first.push_constant(Value::from_number(number));
+            first.write_instruction(OpCode::OpConstant, 1);
+            first.write_byte(index as u8, 1);
+        }
+        first.write_instruction(OpCode::OpReturn, 1);
+        let mut second = Chunk::new();
+        second.write_instruction(OpCode::OpSubtract, 2);
+        second.write_instruction(OpCode::OpReturn, 2);
+
+        first.merge(second).unwrap();
+        assert_eq!(first.instructions().unwrap().len(), 4);
+        let mut vm = Vm::new();
+        vm.load_chunk(first).unwrap();
+        assert!(matches!(
+            vm.run(),
+            Ok(StepResult::Returned(value)) if value.as_number() == 2.0
+        ));
+    }
+
+    fn only_function(chunk: &Chunk) -> Rc<Chunk> {
+        for constant in &chunk.constants {
+            if let Some(Obj::ObjFunction(function)) = constant.as_obj().map(|obj| &**obj) {
+                return Rc::clone(&function.chunk);
+            }
+        }
+        panic!("No function in the chunk.");
+    }
+
+    #[test]
+    fn code_after_return_is_unreachable() {
+        let chunk = only_function(&compile("fun f() { return 1; print 2; }").unwrap());
+        let instructions = chunk.instructions().unwrap();
+        let reachable = chunk.reachability_analysis();
+
+        let first_return = instructions
+            .iter()
+            .position(|(_, op)| matches!(op, OpCode::OpReturn))
+            .unwrap();
+        for (i, (offset, _)) in instructions.iter().enumerate() {
+            assert_eq!(reachable[*offset], i <= first_return, "offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn both_branches_and_loops_are_reachable() {
+        let source = "var a = 1; if (a) print 1; else print 2; while (a < 3) a = a + 1;";
+        let chunk = compile(source).unwrap();
+        let reachable = chunk.reachability_analysis();
+        for (offset, _) in chunk.instructions().unwrap() {
+            assert!(reachable[offset], "offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn merge_rebases_constant_operands() {
+        let mut chunk = compile("print 1.5;").unwrap();
+        chunk.merge(compile("print 2.5;").unwrap()).unwrap();
+        let listing = chunk.dissasemble_to_string("merged").unwrap();
+        assert!(listing.contains("OP_CONSTANT      0000 '1.5'"));
+        assert!(listing.contains("OP_CONSTANT      0001 '2.5'"));
+        assert_eq!(chunk.verify_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn source_map_has_an_entry_per_instruction() {
+        let chunk = compile("var a = \"x\";\nprint a;").unwrap();
+        let map = chunk.to_source_map().unwrap();
+        for (offset, _) in chunk.instructions().unwrap() {
+            let (line, column) = chunk.position(offset);
+            let entry = format!(
+                "{{\"offset\":{},\"line\":{},\"column\":{}}}",
+                offset, line, column
+            );
+            assert!(map.contains(&entry), "{} not in {}", entry, map);
+        }
+        let entries = map.matches("\"offset\":").count();
+        assert_eq!(entries, chunk.instructions().unwrap().len());
+        assert!(map.contains("{\"index\":0,\"type\":\"string\",\"value\":\"a\"}"));
+    }
+
+    #[test]
+    fn constants_are_listed_with_their_types_before_the_code() {
+        let mut chunk = Chunk::new();
+        chunk.constants.push(Value::from_number(2.5));
+        chunk.constants.push(Value::TRUE);
+        chunk.constants.push(Value::NIL);
+        chunk
+            .constants
+            .push(Value::from_string("hello".to_string()));
+        chunk.write_instruction(OpCode::OpReturn, 1);
+
+        let constants = "[0] number: 2.5\n[1] bool: true\n[2] nil\n[3] string: \"hello\"";
+        assert_eq!(chunk.pretty_print_constants(), constants);
+        assert_eq!(
+            chunk.dissasemble_to_string("code").unwrap(),
+            format!(
+                "== code ==\n== constants ==\n{}\n0000    1 OP_RETURN\n",
+                constants
+            )
+        );
+    }
+
+    #[test]
+    fn nested_functions_are_disassembled_indented() {
+        let chunk = compile("fun outer() { fun inner() { return 2; } return inner; }").unwrap();
+        let outer = chunk
+            .constants
+            .iter()
+            .find_map(|constant| match constant.as_obj().map(|obj| &**obj) {
+                Some(Obj::ObjFunction(function)) => return Some(function),
+                _ => return None,
+            })
+            .unwrap();
+
+        let text = disassemble_function(outer).unwrap();
+        assert!(text.starts_with("== outer ==\n"));
+        assert!(text.contains(
+            "\n    == inner ==\n    == constants ==\n    [0] number: 2\n    0000    1 OP_CONSTANT"
+        ));
+        // The rest of outer follows inner's listing at the outer indent
+        assert!(text.contains("    0004    | OP_RETURN\n0002    | OP_GET_LOCAL"));
+    }
+
+    fn integrity(code: &[OpCode], operands: &[u8]) -> Result<(), ChunkError> {
+        let mut bytes: Vec<u8> = code.iter().map(|op| *op as u8).collect();
+        bytes.splice(1..1, operands.iter().copied());
+        return Chunk::from_code(bytes).verify_integrity();
+    }
+
+    #[test]
+    fn compiled_chunks_pass_integrity_checks() {
+        let source = "fun f(a, b = 1) { while (a < b) a = a + 1; return a; } print f(0);";
+        assert_eq!(compile(source).unwrap().verify_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn integrity_checks_catch_broken_code() {
+        use OpCode::*;
+        assert_eq!(integrity(&[OpNil], &[]), Err(ChunkError::MissingReturn));
+        assert_eq!(
+            integrity(&[OpConstant, OpReturn], &[0]),
+            Err(ChunkError::ConstantOutOfRange(0, 0))
+        );
+        assert_eq!(
+            integrity(&[OpJump, OpReturn], &[0, 5]),
+            Err(ChunkError::InvalidJumpTarget(8))
+        );
+        assert_eq!(
+            integrity(&[OpLoop, OpReturn], &[0, 9]),
+            Err(ChunkError::LoopBeforeStart(0))
+        );
+        assert_eq!(
+            integrity(&[OpConstantLong], &[0]),
+            Err(ChunkError::MissingOperands(0))
+        );
+        assert_eq!(
+            Chunk::from_code(vec![0xee, OpReturn as u8]).verify_integrity(),
+            Err(ChunkError::InvalidInstruction(0, 0xee))
+        );
+
+        let mut chunk = compile("print 1;").unwrap();
+        chunk.lines.pop();
+        assert!(matches!(
+            chunk.verify_integrity(),
+            Err(ChunkError::DebugInfoMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn disassembling_an_invalid_chunk_is_an_error() {
+        let missing_constant = Chunk::from_code(vec![OpCode::OpConstant as u8, 7]);
+        let text = missing_constant.dissasemble_to_string("test").unwrap();
+        assert!(text.contains("'<missing>'"), "{}", text);
+        assert!(missing_constant.verify_integrity().is_err());
+
+        let missing_operand = Chunk::from_code(vec![OpCode::OpConstantLong as u8, 0]);
+        assert!(missing_operand.dissasemble_to_string("test").is_err());
+    }
+
+    #[test]
+    fn bin_round_trip_keeps_the_constant_pool() {
+        let source =
+            "var s = \"hi\"; fun f(a, b = 2) { return a + b + 3.75; } print f(-123456789.5);";
+        let chunk = compile(source).unwrap();
+        let decoded = Chunk::from_bin(&chunk.to_bin().unwrap()).unwrap();
+
+        assert!(chunk.diff(&decoded).is_empty());
+        assert_eq!(chunk.lines, decoded.lines);
+        assert_eq!(chunk.columns, decoded.columns);
+        assert_eq!(
+            decoded.pretty_print_constants(),
+            chunk.pretty_print_constants()
+        );
+        assert_eq!(
+            decoded.dissasemble_to_string("code").unwrap(),
+            chunk.dissasemble_to_string("code").unwrap()
+        );
+    }
+
+    #[test]
+    fn bin_round_trip_of_stripped_chunk() {
+        let chunk = compile("print 3.75;").unwrap().clone_without_debug_info();
+        let decoded = Chunk::from_bin(&chunk.to_bin().unwrap()).unwrap();
+        assert!(decoded.lines.is_empty());
+        assert_eq!(decoded.constants[0].as_number(), 3.75);
+    }
+
+    #[test]
+    fn legacy_bin_inlines_numbers() {
+        // `print 69 + 12;` in the format without a flag byte
+        let bin = [1, 1, 69, 1, 1, 1, 12, 1, 3, 1, 25, 1, 0, 1];
+        let chunk = Chunk::from_bin(&bin).unwrap();
+        assert!(chunk.verify().is_ok());
+        assert_eq!(chunk.constants[0].as_number(), 69.0);
+        assert_eq!(chunk.constants[1].as_number(), 12.0);
+    }
+
+    #[test]
+    fn truncated_bin_is_an_error() {
+        let bin = compile("print \"hi\";").unwrap().to_bin().unwrap();
+        for len in 1..bin.len() {
+            assert!(Chunk::from_bin(&bin[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn lists_cant_be_stored_in_a_bin() {
+        let mut chunk = Chunk::new();
+        chunk
+            .write_constant_long(Value::from_obj(Rc::new(Obj::ObjList(vec![]))), 1)
+            .unwrap();
+        chunk.write_instruction(OpCode::OpReturn, 1);
+        assert!(chunk.to_bin().is_err());
+    }
+
+    #[test]
+    fn identical_chunks_have_no_diff() {
+        let chunk = compile("var a = 1; print a + 2;").unwrap();
+        assert!(chunk.diff(&chunk.clone()).is_empty());
+    }
+
+    #[test]
+    fn diff_reports_changed_added_and_removed_bytes() {
+        use OpCode::*;
+        let old = Chunk::from_code(vec![OpNil as u8, OpPrint as u8, OpReturn as u8]);
+        let new = Chunk::from_code(vec![
+            OpTrue as u8,
+            OpPrint as u8,
+            OpNil as u8,
+            OpReturn as u8,
+        ]);
+
+        let diffs = old.diff(&new);
+        assert_eq!(diffs.len(), 2);
+        assert!(
+            matches!(diffs[0], ChunkDiff::Changed(0, a, b) if a == OpNil as u8 && b == OpTrue as u8)
+        );
+        assert!(matches!(diffs[1], ChunkDiff::Added(2, byte) if byte == OpNil as u8));
+
+        let diffs = new.diff(&old);
+        assert!(matches!(diffs[1], ChunkDiff::Removed(2, byte) if byte == OpNil as u8));
+    }
+
+    #[test]
+    fn diff_reports_constant_changes() {
+        let old = compile("print 5; print 2;").unwrap();
+        let new = compile("print 5; print 3; print 4;").unwrap();
+
+        let diffs: Vec<_> = old
+            .diff(&new)
+            .into_iter()
+            .filter(|diff| {
+                return matches!(
+                    diff,
+                    ChunkDiff::ConstantAdded(..)
+                        | ChunkDiff::ConstantRemoved(..)
+                        | ChunkDiff::ConstantChanged(..)
+                );
+            })
+            .collect();
+        assert_eq!(diffs.len(), 2);
+        assert!(matches!(&diffs[0], ChunkDiff::ConstantChanged(1, a, b)
+            if a.as_number() == 2.0 && b.as_number() == 3.0));
+        assert!(matches!(&diffs[1], ChunkDiff::ConstantAdded(2, c) if c.as_number() == 4.0));
+    }
 }
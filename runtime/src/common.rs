@@ -1,5 +1,8 @@
 pub const DEBUG_TRACE_EXECUTION: bool = true;
 pub const DEBUG_PRINT_CODE: bool = true;
+// Blocks clean up their locals with OpPushFrame/OpPopFrame instead of
+// counted pops, see the `scope-frames` feature.
+pub const SCOPE_FRAMES: bool = cfg!(feature = "scope-frames");
 
 pub fn dissasemble_error(msg: String) -> String {
     return format!("[DISSASEMBLE]: {}", msg);
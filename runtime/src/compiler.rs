@@ -1,10 +1,14 @@
-use std::fs::File;
-use std::io::prelude::*;
+use std::fmt;
+use std::fs;
+use std::mem;
+use std::rc::Rc;
 
-use crate::chunk::{Chunk, OpCode};
-use crate::common::{compile_error, DEBUG_PRINT_CODE};
+use crate::chunk::{disassemble_function, Chunk, OpCode, BYTECODE_VERSION, MAX_LONG_CONSTANT};
+use crate::common::{compile_error, DEBUG_PRINT_CODE, SCOPE_FRAMES};
+use crate::object::{Obj, ObjFunction};
 use crate::scanner::{Scanner, Token, TokenType};
 use crate::value::{Number, Value};
+use crate::vm::is_native;
 
 macro_rules! rule {
     ($prefix:expr, $infix:expr, $precedence:expr) => {
@@ -16,54 +20,71 @@ macro_rules! rule {
     };
 }
 
-const RULES: [ParseRule; 40] = [
-    rule!(Some(Compiler::grouping), None, Precedence::None), // TOKEN_LEFT_PAREN
-    rule!(None, None, Precedence::None),                     // TOKEN_RIGHT_PAREN
-    rule!(None, None, Precedence::None),                     // TOKEN_LEFT_BRACE
-    rule!(None, None, Precedence::None),                     // TOKEN_RIGHT_BRACE
-    rule!(None, None, Precedence::None),                     // TOKEN_COMMA
-    rule!(None, None, Precedence::None),                     // TOKEN_DOT
+const RULES: [ParseRule; 53] = [
+    rule!(
+        Some(Compiler::grouping),
+        Some(Compiler::call),
+        Precedence::Call
+    ), // TOKEN_LEFT_PAREN
+    rule!(None, None, Precedence::None), // TOKEN_RIGHT_PAREN
+    rule!(None, None, Precedence::None), // TOKEN_LEFT_BRACE
+    rule!(None, None, Precedence::None), // TOKEN_RIGHT_BRACE
+    rule!(None, None, Precedence::None), // TOKEN_COMMA
+    rule!(None, None, Precedence::None), // TOKEN_DOT
     rule!(
         Some(Compiler::unary),
         Some(Compiler::binary),
         Precedence::Term
     ), // TOKEN_MINUS
-    rule!(None, Some(Compiler::binary), Precedence::Term),   // TOKEN_PLUS
-    rule!(None, None, Precedence::None),                     // TOKEN_SEMICOLON
+    rule!(None, Some(Compiler::binary), Precedence::Term), // TOKEN_PLUS
+    rule!(None, None, Precedence::None), // TOKEN_SEMICOLON
     rule!(None, Some(Compiler::binary), Precedence::Factor), // TOKEN_SLASH
     rule!(None, Some(Compiler::binary), Precedence::Factor), // TOKEN_STAR
-    rule!(Some(Compiler::unary), None, Precedence::None),    // TOKEN_BANG
+    rule!(None, Some(Compiler::binary), Precedence::Factor), // TOKEN_PERCENT
+    rule!(Some(Compiler::unary), None, Precedence::None), // TOKEN_BANG
     rule!(None, Some(Compiler::binary), Precedence::Equality), // TOKEN_BANG_EQUAL
-    rule!(None, None, Precedence::None),                     // TOKEN_EQUAL
+    rule!(None, None, Precedence::None), // TOKEN_EQUAL
     rule!(None, Some(Compiler::binary), Precedence::Equality), // TOKEN_EQUAL_EQUAL
     rule!(None, Some(Compiler::binary), Precedence::Comparison), // TOKEN_GREATER
     rule!(None, Some(Compiler::binary), Precedence::Comparison), // TOKEN_GREATER_EQUAL
+    rule!(None, Some(Compiler::binary), Precedence::Shift), // TOKEN_GREATER_GREATER
+    rule!(None, Some(Compiler::binary), Precedence::Shift), // TOKEN_GREATER_GREATER_GREATER
     rule!(None, Some(Compiler::binary), Precedence::Comparison), // TOKEN_LESS
     rule!(None, Some(Compiler::binary), Precedence::Comparison), // TOKEN_LESS_EQUAL
-    rule!(None, None, Precedence::None),                     // TOKEN_IDENTIFIER
-    rule!(None, None, Precedence::None),                     // TOKEN_STRING
-    rule!(Some(Compiler::number), None, Precedence::None),   // TOKEN_NUMBER
-    rule!(None, None, Precedence::None),                     // TOKEN_AND
-    rule!(None, None, Precedence::None),                     // TOKEN_CLASS
-    rule!(None, None, Precedence::None),                     // TOKEN_ELSE
-    rule!(Some(Compiler::literal), None, Precedence::None),  // TOKEN_FALSE
-    rule!(None, None, Precedence::None),                     // TOKEN_FOR
-    rule!(None, None, Precedence::None),                     // TOKEN_FUN
-    rule!(None, None, Precedence::None),                     // TOKEN_IF
-    rule!(Some(Compiler::literal), None, Precedence::None),  // TOKEN_NIL
-    rule!(None, None, Precedence::None),                     // TOKEN_OR
-    rule!(None, None, Precedence::None),                     // TOKEN_PRINT
-    rule!(None, None, Precedence::None),                     // TOKEN_RETURN
-    rule!(None, None, Precedence::None),                     // TOKEN_SUPER
-    rule!(None, None, Precedence::None),                     // TOKEN_THIS
-    rule!(Some(Compiler::literal), None, Precedence::None),  // TOKEN_TRUE
-    rule!(None, None, Precedence::None),                     // TOKEN_VAR
-    rule!(None, None, Precedence::None),                     // TOKEN_WHILE
-    rule!(None, None, Precedence::None),                     // TOKEN_ERROR
-    rule!(None, None, Precedence::None),                     // TOKEN_EOF
+    rule!(None, Some(Compiler::binary), Precedence::Shift), // TOKEN_LESS_LESS
+    rule!(None, Some(Compiler::binary), Precedence::Range), // TOKEN_DOT_DOT
+    rule!(None, Some(Compiler::binary), Precedence::Range), // TOKEN_DOT_DOT_EQUAL
+    rule!(None, Some(Compiler::coalesce), Precedence::Or), // TOKEN_QUESTION_QUESTION
+    rule!(Some(Compiler::unary), None, Precedence::None), // TOKEN_MINUS_MINUS
+    rule!(None, None, Precedence::None), // TOKEN_PLUS_PLUS
+    rule!(Some(Compiler::variable), None, Precedence::None), // TOKEN_IDENTIFIER
+    rule!(Some(Compiler::string), None, Precedence::None), // TOKEN_STRING
+    rule!(Some(Compiler::number), None, Precedence::None), // TOKEN_NUMBER
+    rule!(None, None, Precedence::None), // TOKEN_AND
+    rule!(None, None, Precedence::None), // TOKEN_CLASS
+    rule!(None, None, Precedence::None), // TOKEN_ELSE
+    rule!(Some(Compiler::literal), None, Precedence::None), // TOKEN_FALSE
+    rule!(None, None, Precedence::None), // TOKEN_FOR
+    rule!(Some(Compiler::lambda), None, Precedence::None), // TOKEN_FUN
+    rule!(None, None, Precedence::None), // TOKEN_IF
+    rule!(None, Some(Compiler::binary), Precedence::Comparison), // TOKEN_IN
+    rule!(None, Some(Compiler::binary), Precedence::Comparison), // TOKEN_IS
+    rule!(Some(Compiler::literal), None, Precedence::None), // TOKEN_NIL
+    rule!(None, None, Precedence::None), // TOKEN_OR
+    rule!(None, None, Precedence::None), // TOKEN_PRINT
+    rule!(None, None, Precedence::None), // TOKEN_RETURN
+    rule!(None, None, Precedence::None), // TOKEN_SUPER
+    rule!(None, None, Precedence::None), // TOKEN_THIS
+    rule!(Some(Compiler::literal), None, Precedence::None), // TOKEN_TRUE
+    rule!(Some(Compiler::unary), None, Precedence::None), // TOKEN_TYPEOF
+    rule!(None, None, Precedence::None), // TOKEN_VAR
+    rule!(None, None, Precedence::None), // TOKEN_WHILE
+    rule!(None, None, Precedence::None), // TOKEN_WRITE
+    rule!(None, None, Precedence::None), // TOKEN_ERROR
+    rule!(None, None, Precedence::None), // TOKEN_EOF
 ];
 
-#[derive(PartialEq, PartialOrd, Debug, Clone, Copy)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 enum Precedence {
     None = 0,
     Assignment = 1, // =
@@ -71,11 +92,41 @@ enum Precedence {
     And = 3,        // and
     Equality = 4,   // == !=
     Comparison = 5, // < > <= >=
-    Term = 6,       // + -
-    Factor = 7,     // * /
-    Unary = 8,      // ! -
-    Call = 9,       // . ()
-    Primary = 10,
+    Range = 6,      // .. ..=
+    Shift = 7,      // << >> >>>
+    Term = 8,       // + -
+    Factor = 9,     // * / %
+    Unary = 10,     // ! -
+    Call = 11,      // . ()
+    Primary = 12,
+}
+
+// Binding strength is the discriminant, higher binds tighter.
+impl Precedence {
+    fn is_lower_than(self, other: Precedence) -> bool {
+        return (self as u8) < (other as u8);
+    }
+
+    fn is_higher_than(self, other: Precedence) -> bool {
+        return (self as u8) > (other as u8);
+    }
+}
+
+// Compiles a whole program with a fresh compiler, for callers that don't
+// need to configure or reuse one.
+pub fn compile(source: &str) -> Result<Chunk, Vec<CompileError>> {
+    return Compiler::new(source.to_string()).to_chunk(Chunk::new());
+}
+
+// FNV-1a, unlike `DefaultHasher` it's the same across Rust versions so
+// cache entries outlive toolchain updates.
+fn source_hash(source: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in source.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    return hash;
 }
 
 fn byte_to_prec(byte: u8) -> Result<Precedence, String> {
@@ -86,11 +137,13 @@ fn byte_to_prec(byte: u8) -> Result<Precedence, String> {
         3 => return Ok(Precedence::And),
         4 => return Ok(Precedence::Equality),
         5 => return Ok(Precedence::Comparison),
-        6 => return Ok(Precedence::Term),
-        7 => return Ok(Precedence::Factor),
-        8 => return Ok(Precedence::Unary),
-        9 => return Ok(Precedence::Call),
-        10 => return Ok(Precedence::Primary),
+        6 => return Ok(Precedence::Range),
+        7 => return Ok(Precedence::Shift),
+        8 => return Ok(Precedence::Term),
+        9 => return Ok(Precedence::Factor),
+        10 => return Ok(Precedence::Unary),
+        11 => return Ok(Precedence::Call),
+        12 => return Ok(Precedence::Primary),
         _ => {
             return Err(format!(
                 "Invalid conversion to precedence from byte: '{}'\nPrecedence doesn't exist.",
@@ -100,7 +153,7 @@ fn byte_to_prec(byte: u8) -> Result<Precedence, String> {
     };
 }
 
-type ParseFn = fn(&mut Compiler);
+type ParseFn = fn(&mut Compiler, bool);
 
 #[derive(Debug)]
 struct ParseRule {
@@ -109,15 +162,103 @@ struct ParseRule {
     precedence: Precedence,
 }
 
+const UINT8_COUNT: usize = u8::MAX as usize + 1;
+
+// Nested expressions, statements and function bodies recurse in the
+// compiler, deeper input is reported instead of overflowing the stack.
+// They share one count.
+const MAX_NESTING_DEPTH: usize = 1000;
+
+// Tokens scanned between calls to the progress callback.
+const PROGRESS_INTERVAL: usize = 100;
+
+// Closures can't derive Debug, so only whether one is set is shown.
+#[derive(Default)]
+struct ProgressCallback(Option<Box<dyn Fn(f32)>>);
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => return write!(f, "ProgressCallback(Some(..))"),
+            None => return write!(f, "ProgressCallback(None)"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Local {
+    name: String,
+    depth: i32,
+}
+
+// A diagnostic collected while compiling, printing it gives the usual
+// `[Line L] Error at 'x': message` form.
+#[derive(Debug, Clone)]
+pub struct CompileError {
+    pub line: i32,
+    pub column: i32,
+    pub message: String,
+    // `None` for lexical errors, which have no token to point at.
+    pub token_lexeme: Option<String>,
+    // Source the parser was looking at, from the offending token to the end
+    // of its line.
+    pub context: Option<String>,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[Line {}] Error", self.line)?;
+        match &self.token_lexeme {
+            Some(lexeme) if lexeme.is_empty() => write!(f, " at end")?,
+            Some(lexeme) => write!(f, " at '{}'", lexeme)?,
+            None => (),
+        }
+        write!(f, ": {}", self.message)?;
+        if let Some(context) = self.context.as_deref().map(str::trim_end) {
+            if !context.is_empty() {
+                write!(f, "\n    {}", context)?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+// What the chunk being compiled belongs to, decides what `return` may do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FunctionType {
+    Script,
+    Function,
+    Method,
+    // Always returns the instance in slot 0.
+    Initializer,
+}
+
 #[derive(Debug)]
 pub struct Compiler {
     current: Option<Token>,
     previous: Option<Token>,
     compiling_chunk: Option<Chunk>,
-    compiling_file: Option<File>,
-    had_error: bool,
+    errors: Vec<CompileError>,
     panic_mode: bool,
     scanner: Scanner,
+    locals: Vec<Local>,
+    scope_depth: i32,
+    // Whether each open block has pushed its scope frame, innermost last.
+    // Only blocks that declare locals push one.
+    scope_frames: Vec<bool>,
+    function_type: FunctionType,
+    // Offset of the last OpCall emitted, to spot calls in tail position.
+    last_call: Option<usize>,
+    // End of the last OpGetGlobal of a native's name, a call right after it
+    // becomes OpCallNative.
+    native_callee: Option<usize>,
+    nesting_depth: usize,
+    max_nesting_depth: usize,
+    // Set once the nesting limit is hit, the rest of the input is skipped
+    // and the open blocks unwind without recovering.
+    nesting_overflowed: bool,
+    token_count: usize,
+    progress_callback: ProgressCallback,
 }
 
 impl Compiler {
@@ -128,57 +269,712 @@ impl Compiler {
             current: None,
             previous: None,
             compiling_chunk: None,
-            compiling_file: None,
-            had_error: false,
+            errors: vec![],
             panic_mode: false,
             scanner,
+            locals: vec![],
+            scope_depth: 0,
+            scope_frames: vec![],
+            function_type: FunctionType::Script,
+            last_call: None,
+            native_callee: None,
+            nesting_depth: 0,
+            max_nesting_depth: MAX_NESTING_DEPTH,
+            nesting_overflowed: false,
+            token_count: 0,
+            progress_callback: ProgressCallback::default(),
         }
     }
 
-    pub fn to_file(&mut self, path: &str) -> Result<(), String> {
-        match File::create(path) {
-            Ok(file) => {
-                self.had_error = false;
-                self.panic_mode = false;
-                self.compiling_file = Some(file);
+    // A thread with a small stack may need a lower limit than the default.
+    pub fn with_max_nesting_depth(mut self, max_nesting_depth: usize) -> Self {
+        self.max_nesting_depth = max_nesting_depth;
+        return self;
+    }
 
-                self.advance();
-                self.expression();
-                self.consume(TokenType::EOF, "Expect end of expression.".to_string());
-                self.end();
+    // Called with the fraction of the source scanned every
+    // `PROGRESS_INTERVAL` tokens and once the end is reached.
+    pub fn with_progress_callback(mut self, callback: Box<dyn Fn(f32)>) -> Self {
+        self.progress_callback = ProgressCallback(Some(callback));
+        return self;
+    }
 
-                Ok(())
-            }
-            Err(message) => {
-                return Err(compile_error(format!(
-                    "Error creating file:\n\r{}",
-                    message
-                )));
+    // Prepares to compile `source` with the scanner and buffers of the
+    // previous compilation, like a new compiler would.
+    pub fn reset(&mut self, source: String) {
+        self.scanner.reset(source);
+        self.current = None;
+        self.previous = None;
+        self.compiling_chunk = None;
+        self.errors.clear();
+        self.panic_mode = false;
+        self.locals.clear();
+        self.scope_depth = 0;
+        self.scope_frames.clear();
+        self.function_type = FunctionType::Script;
+        self.last_call = None;
+        self.native_callee = None;
+        self.nesting_depth = 0;
+        self.nesting_overflowed = false;
+        self.token_count = 0;
+    }
+
+    // Writes the compiled program as a bin, see `Chunk::to_bin`.
+    pub fn to_file(&mut self, path: &str) -> Result<(), String> {
+        let chunk = match self.to_chunk(Chunk::new()) {
+            Ok(chunk) => chunk,
+            Err(_) => return Err(self.error_report()),
+        };
+        if let Err(error) = fs::write(path, chunk.to_bin()?) {
+            return Err(compile_error(format!("Error writing file:\n\r{}", error)));
+        }
+        return Ok(());
+    }
+
+    // Compiles `source` unless `cache_dir` already holds its bin, the flag
+    // tells whether the cache was hit.
+    pub fn compile_cached(source: &str, cache_dir: &str) -> Result<(Chunk, bool), String> {
+        let path = format!(
+            "{}/{}-{:016x}.bin",
+            cache_dir.trim_end_matches('/'),
+            BYTECODE_VERSION,
+            source_hash(source)
+        );
+        if let Ok(Ok(chunk)) = fs::read(&path).map(|bin| Chunk::from_bin(&bin)) {
+            if chunk.verify().is_ok() {
+                return Ok((chunk, true));
             }
         }
+
+        let mut compiler = Compiler::new(source.to_string());
+        let chunk = match compiler.to_chunk(Chunk::new()) {
+            Ok(chunk) => chunk,
+            Err(_) => return Err(compiler.error_report()),
+        };
+        // A cache that can't be written only costs a recompile
+        if let Ok(bin) = chunk.to_bin() {
+            let _ = fs::create_dir_all(cache_dir).and_then(|_| fs::write(&path, &bin));
+        }
+        return Ok((chunk, false));
     }
 
-    pub fn to_chunk(&mut self, chunk: Chunk) -> Option<Chunk> {
-        self.had_error = false;
+    pub fn to_chunk(&mut self, chunk: Chunk) -> Result<Chunk, Vec<CompileError>> {
+        self.errors.clear();
+        self.panic_mode = false;
+        self.compiling_chunk = Some(chunk);
+
+        self.advance();
+        while !self.match_token(TokenType::EOF) {
+            self.declaration();
+        }
+        self.end();
+
+        if !self.errors.is_empty() {
+            return Err(self.errors.clone());
+        }
+        return Ok(self.compiling_chunk.take().unwrap_or_default());
+    }
+
+    // Compiles the source as a single expression whose value the chunk
+    // returns, for evaluating snippets like a calculator would.
+    pub fn to_expression_chunk(&mut self, chunk: Chunk) -> Result<Chunk, Vec<CompileError>> {
+        self.errors.clear();
         self.panic_mode = false;
         self.compiling_chunk = Some(chunk);
 
         self.advance();
         self.expression();
+        self.match_token(TokenType::Semicolon);
         self.consume(TokenType::EOF, "Expect end of expression.".to_string());
         self.end();
 
-        return self.compiling_chunk.take();
+        if !self.errors.is_empty() {
+            return Err(self.errors.clone());
+        }
+        return Ok(self.compiling_chunk.take().unwrap_or_default());
+    }
+
+    fn error_report(&self) -> String {
+        let mut report = String::new();
+        for error in &self.errors {
+            report.push_str(&format!("{}\n", error));
+        }
+        return compile_error(format!("Failed to compile:\n{}", report.trim_end()));
+    }
+
+    // Diagnostics from the last compilation, in the order they were found.
+    pub fn errors(&self) -> &[CompileError] {
+        return &self.errors;
     }
 
     fn expression(&mut self) {
         self.parse_precedence(Precedence::Assignment);
     }
 
-    fn number(&mut self) {
+    fn declaration(&mut self) {
+        if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else if self.check(TokenType::Fun)
+            && self.scanner.peek_token().get_type() == TokenType::Identifier
+        {
+            // `fun` followed by a name, otherwise it's a lambda expression
+            self.advance();
+            self.fun_declaration();
+        } else {
+            self.statement();
+        }
+
+        // Recovering inside the blocks being unwound would report a missing
+        // '}' for each of them.
+        if self.panic_mode && !(self.nesting_overflowed && self.nesting_depth > 0) {
+            self.nesting_overflowed = false;
+            self.synchronize();
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.".to_string());
+
+        if self.match_token(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::OpNil as u8);
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.".to_string(),
+        );
+
+        self.define_variable(global);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.".to_string());
+        let name = match &self.previous {
+            Some(previous) => previous.get_lexeme(),
+            None => return,
+        };
+
+        // Initialized straight away so the body can call itself
+        self.mark_initialized();
+        self.function(Some(name), FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    // Declares the variable named by the next token. Returns the constant
+    // holding its name for globals, locals live on the stack instead.
+    fn parse_variable(&mut self, message: String) -> usize {
+        self.consume(TokenType::Identifier, message);
+        let name = match &self.previous {
+            Some(previous) => previous.get_lexeme(),
+            None => return 0,
+        };
+
+        if self.scope_depth > 0 {
+            // The initializer's value on the stack becomes the local's slot,
+            // it can't be read until the initializer is done.
+            self.declare_local(name);
+            return 0;
+        }
+
+        return self.identifier_constant(name);
+    }
+
+    // Every use of a global shares one constant for its name.
+    fn identifier_constant(&mut self, name: String) -> usize {
+        let existing = self.current_chunk().and_then(|chunk| {
+            chunk
+                .constants
+                .iter()
+                .position(|constant| constant.is_string() && constant.as_string() == name)
+        });
+        if let Some(constant) = existing {
+            return constant;
+        }
+
+        match self.make_constant(Value::from_string(name)) {
+            Ok(constant) => return constant,
+            Err(err) => {
+                self.error(err);
+                return 0;
+            }
+        }
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_variable(OpCode::OpDefineGlobal, global);
+    }
+
+    // Globals whose name doesn't fit a one byte index use the long variant.
+    fn emit_variable(&mut self, op: OpCode, arg: usize) {
+        let long_op = match op {
+            OpCode::OpDefineGlobal => OpCode::OpDefineGlobalLong,
+            OpCode::OpGetGlobal => OpCode::OpGetGlobalLong,
+            OpCode::OpSetGlobal => OpCode::OpSetGlobalLong,
+            _ => return self.emit_bytes(op as u8, arg as u8),
+        };
+        if arg <= u8::MAX as usize {
+            return self.emit_bytes(op as u8, arg as u8);
+        }
+
+        let [_, high, middle, low] = (arg as u32).to_be_bytes();
+        self.emit_bytes(long_op as u8, high);
+        self.emit_bytes(middle, low);
+    }
+
+    fn declare_local(&mut self, name: String) {
+        let redeclared = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth >= self.scope_depth)
+            .any(|local| local.name == name);
+        if redeclared {
+            self.error("Already a variable with this name in this scope.".to_string());
+        }
+
+        self.push_scope_frame();
+        self.add_local(name);
+    }
+
+    // Emitted ahead of the block's first local, while the stack holds only
+    // the locals of enclosing blocks.
+    fn push_scope_frame(&mut self) {
+        if SCOPE_FRAMES && self.scope_frames.last() == Some(&false) {
+            self.scope_frames.pop();
+            self.scope_frames.push(true);
+            self.emit_byte(OpCode::OpPushFrame as u8);
+        }
+    }
+
+    fn mark_initialized(&mut self) {
+        if self.scope_depth == 0 {
+            return;
+        }
+
+        if let Some(local) = self.locals.last_mut() {
+            local.depth = self.scope_depth;
+        }
+    }
+
+    // Slot of the innermost local called `name`, `None` if it isn't a local.
+    fn resolve_local(&mut self, name: &str) -> Option<u8> {
+        let slot = self.locals.iter().rposition(|local| local.name == name)?;
+        if self.locals[slot].depth == -1 {
+            self.error("Cannot read local variable in its own initializer.".to_string());
+        }
+        return Some(slot as u8);
+    }
+
+    // (name, scope depth, stack slot) of every local in scope. Locals still
+    // in their initializer report the depth they are being declared at.
+    #[cfg(debug_assertions)]
+    pub fn dump_locals(&self) -> Vec<(String, usize, usize)> {
+        return self
+            .locals
+            .iter()
+            .enumerate()
+            .map(|(slot, local)| {
+                let depth = match local.depth {
+                    -1 => self.scope_depth,
+                    depth => depth,
+                };
+                (local.name.clone(), depth as usize, slot)
+            })
+            .collect();
+    }
+
+    fn add_local(&mut self, name: String) {
+        if self.locals.len() == UINT8_COUNT {
+            self.error_at_current("Too many local variables in function.".to_string());
+            return;
+        }
+
+        // Declared but uninitialized until mark_initialized
+        self.locals.push(Local { name, depth: -1 });
+    }
+
+    fn statement(&mut self) {
+        if !self.enter_nesting("Statement nesting too deep.") {
+            return;
+        }
+
+        if self.match_token(TokenType::Print) {
+            self.print_statement();
+        } else if self.match_token(TokenType::Write) {
+            self.write_statement();
+        } else if self.match_token(TokenType::While) {
+            self.while_statement();
+        } else if self.match_token(TokenType::For) {
+            self.for_statement();
+        } else if self.match_token(TokenType::If) {
+            self.if_statement();
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_token(TokenType::LeftBrace) {
+            self.compile_block();
+        } else {
+            self.expression_statement();
+        }
+        self.nesting_depth -= 1;
+    }
+
+    // The value isn't stored anywhere, so it's discarded right away.
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after expression.".to_string(),
+        );
+        self.emit_byte(OpCode::OpPop as u8);
+    }
+
+    // Bodies of `if`, `while` and `for` reach this through `statement`.
+    // Function bodies use `block` directly, their parameters already opened
+    // the scope the body's locals belong to.
+    fn compile_block(&mut self) {
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+    }
+
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            self.declaration();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.".to_string());
+    }
+
+    // Compiles parameters and body into a chunk of their own and emits the
+    // resulting function as a constant.
+    fn function(&mut self, name: Option<String>, function_type: FunctionType) {
+        if !self.enter_nesting("Function nesting too deep.") {
+            return;
+        }
+        let enclosing_chunk = self.compiling_chunk.replace(Chunk::new());
+        // Slot 0 holds the function being called
+        let enclosing_locals = mem::replace(
+            &mut self.locals,
+            vec![Local {
+                name: String::new(),
+                depth: 0,
+            }],
+        );
+        let enclosing_depth = mem::replace(&mut self.scope_depth, 0);
+        let enclosing_frames = mem::take(&mut self.scope_frames);
+        let enclosing_type = mem::replace(&mut self.function_type, function_type);
+        // Offsets of the enclosing chunk mean nothing in this one
+        let enclosing_call = self.last_call.take();
+        let enclosing_callee = self.native_callee.take();
+
+        // The call frame already bounds the parameters and body locals, so
+        // this scope has no scope frame and is never ended.
+        self.scope_depth += 1;
+        self.consume(
+            TokenType::LeftParen,
+            "Expect '(' after function name.".to_string(),
+        );
+
+        let mut arity: usize = 0;
+        // Each default is compiled in order ahead of the body, a call that
+        // omits arguments starts at the first default it needs.
+        let mut entry_points: Vec<usize> = vec![];
+        if !self.check(TokenType::RightParen) {
+            loop {
+                arity += 1;
+                if arity > u8::MAX as usize {
+                    self.error_at_current("Can't have more than 255 parameters.".to_string());
+                }
+
+                self.consume(TokenType::Identifier, "Expect parameter name.".to_string());
+                if let Some(previous) = &self.previous {
+                    let name = previous.get_lexeme();
+                    self.declare_local(name);
+                }
+
+                if self.match_token(TokenType::Equal) {
+                    entry_points.push(self.current_chunk_len());
+                    self.expression();
+                } else if !entry_points.is_empty() {
+                    self.error("Expect default value after an optional parameter.".to_string());
+                }
+                self.mark_initialized();
+
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        entry_points.push(self.current_chunk_len());
+
+        self.consume(
+            TokenType::RightParen,
+            "Expect ')' after parameters.".to_string(),
+        );
+        self.consume(
+            TokenType::LeftBrace,
+            "Expect '{' before function body.".to_string(),
+        );
+        self.block();
+        self.emit_implicit_return();
+
+        // Printed as part of the enclosing chunk when DEBUG_PRINT_CODE is on
+        let chunk = self.compiling_chunk.take().unwrap_or_default();
+
+        self.compiling_chunk = enclosing_chunk;
+        self.locals = enclosing_locals;
+        self.scope_depth = enclosing_depth;
+        self.scope_frames = enclosing_frames;
+        self.function_type = enclosing_type;
+        self.last_call = enclosing_call;
+        self.native_callee = enclosing_callee;
+
+        let arity = arity.min(u8::MAX as usize) as u8;
+        let function = ObjFunction {
+            arity,
+            min_arity: arity.saturating_sub((entry_points.len() - 1) as u8),
+            entry_points,
+            upvalue_count: 0,
+            chunk: Rc::new(chunk),
+            name,
+        };
+        self.emit_constant(Value::from_obj(Rc::new(Obj::ObjFunction(function))));
+        self.nesting_depth -= 1;
+    }
+
+    fn begin_scope(&mut self) {
+        self.scope_depth += 1;
+        self.scope_frames.push(false);
+    }
+
+    fn end_scope(&mut self) {
+        self.scope_depth -= 1;
+        let framed = self.scope_frames.pop().unwrap_or(false);
+
+        let mut pop_count = 0;
+        while let Some(local) = self.locals.last() {
+            if local.depth <= self.scope_depth {
+                break;
+            }
+            self.locals.pop();
+            pop_count += 1;
+        }
+
+        if framed {
+            self.emit_byte(OpCode::OpPopFrame as u8);
+            return;
+        }
+        while pop_count > 0 {
+            let count = pop_count.min(u8::MAX as usize);
+            self.emit_pop_count(count as u8);
+            pop_count -= count;
+        }
+    }
+
+    // One instruction for any number of pops, a lone pop stays `OpPop`.
+    fn emit_pop_count(&mut self, count: u8) {
+        match count {
+            0 => (),
+            1 => self.emit_byte(OpCode::OpPop as u8),
+            _ => self.emit_bytes(OpCode::OpPopN as u8, count),
+        }
+    }
+
+    fn print_statement(&mut self) {
+        let mut value_count: usize = 0;
+        loop {
+            self.expression();
+            if value_count == u8::MAX as usize {
+                self.error("Can't print more than 255 values.".to_string());
+            }
+            value_count += 1;
+
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after value.".to_string());
+
+        match value_count {
+            1 => self.emit_byte(OpCode::OpPrint as u8),
+            _ => self.emit_bytes(OpCode::OpPrintMany as u8, value_count as u8),
+        }
+    }
+
+    // `print` without the trailing newline.
+    fn write_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.".to_string());
+        self.emit_byte(OpCode::OpWrite as u8);
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.".to_string());
+        self.expression();
+        self.consume(
+            TokenType::RightParen,
+            "Expect ')' after condition.".to_string(),
+        );
+
+        let then_jump = self.emit_jump(OpCode::OpJumpIfFalse);
+        self.emit_byte(OpCode::OpPop as u8);
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::OpJump);
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::OpPop as u8);
+
+        // An `else` binds to the nearest `if`
+        if self.match_token(TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn return_statement(&mut self) {
+        if self.function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.".to_string());
+        }
+
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_implicit_return();
+            return;
+        }
+        if self.function_type == FunctionType::Initializer {
+            self.error("Can't return a value from an initializer.".to_string());
+        }
+
+        self.expression();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after return value.".to_string(),
+        );
+
+        // A call that is the last thing evaluated can reuse this frame
+        let call_end = self.last_call.map(|offset| offset + 2);
+        if call_end == Some(self.current_chunk_len()) {
+            if let Some(offset) = self.last_call {
+                self.patch_byte(offset, OpCode::OpTailCall as u8);
+            }
+        }
+        self.emit_return();
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk_len();
+        self.consume(
+            TokenType::LeftParen,
+            "Expect '(' after 'while'.".to_string(),
+        );
+        self.expression();
+        self.consume(
+            TokenType::RightParen,
+            "Expect ')' after condition.".to_string(),
+        );
+
+        let exit_jump = self.emit_jump(OpCode::OpJumpIfFalse);
+        self.emit_byte(OpCode::OpPop as u8);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::OpPop as u8);
+    }
+
+    fn for_statement(&mut self) {
+        // Variables declared in the initializer only live for the loop
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.".to_string());
+        if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else if !self.match_token(TokenType::Semicolon) {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk_len();
+        let mut exit_jump = None;
+        if !self.match_token(TokenType::Semicolon) {
+            self.expression();
+            self.consume(
+                TokenType::Semicolon,
+                "Expect ';' after loop condition.".to_string(),
+            );
+
+            // Jump out of the loop if the condition is false
+            exit_jump = Some(self.emit_jump(OpCode::OpJumpIfFalse));
+            self.emit_byte(OpCode::OpPop as u8);
+        }
+
+        if !self.match_token(TokenType::RightParen) {
+            // The increment runs after the body, so jump over it first
+            let body_jump = self.emit_jump(OpCode::OpJump);
+            let increment_start = self.current_chunk_len();
+            self.expression();
+            self.emit_byte(OpCode::OpPop as u8);
+            self.consume(
+                TokenType::RightParen,
+                "Expect ')' after for clauses.".to_string(),
+            );
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::OpPop as u8);
+        }
+
+        self.end_scope();
+    }
+
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while !self.check(TokenType::EOF) {
+            if let Some(previous) = &self.previous {
+                if previous.get_type() == TokenType::Semicolon {
+                    return;
+                }
+            }
+
+            if let Some(current) = &self.current {
+                match current.get_type() {
+                    TokenType::Class
+                    | TokenType::Fun
+                    | TokenType::Var
+                    | TokenType::For
+                    | TokenType::If
+                    | TokenType::While
+                    | TokenType::Print
+                    | TokenType::Write
+                    | TokenType::Return => return,
+                    _ => (),
+                }
+            }
+
+            self.advance();
+        }
+    }
+
+    fn number(&mut self, _can_assign: bool) {
         if let Some(previous) = &self.previous {
             match previous.get_lexeme().parse::<Number>() {
-                Ok(value) => self.emit_constant(Value::from_number(value)),
+                // Digits alone can still overflow to infinity
+                Ok(value) if !value.is_finite() => {
+                    self.error("Number literal out of range.".to_string())
+                }
+                Ok(value) => self.emit_number(value),
                 Err(err) => {
                     self.error_at_current(format!("Unable to parse value to number.\n\r{}", err))
                 }
@@ -186,7 +982,27 @@ impl Compiler {
         }
     }
 
-    fn grouping(&mut self) {
+    // 0 and 1 are common enough to get opcodes of their own, anything else
+    // goes through the constant pool.
+    fn emit_number(&mut self, value: Number) {
+        if value == 0.0 {
+            self.emit_byte(OpCode::OpZero as u8);
+        } else if value == 1.0 {
+            self.emit_byte(OpCode::OpOne as u8);
+        } else {
+            self.emit_constant(Value::from_number(value));
+        }
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        if let Some(previous) = &self.previous {
+            let lexeme = previous.get_lexeme();
+            let chars = lexeme[1..lexeme.len() - 1].to_string();
+            self.emit_constant(Value::from_string(chars));
+        }
+    }
+
+    fn grouping(&mut self, _can_assign: bool) {
         self.expression();
         self.consume(
             TokenType::RightParen,
@@ -194,25 +1010,28 @@ impl Compiler {
         )
     }
 
-    fn unary(&mut self) {
-        let operator_type = if let Some(previous) = &self.previous {
-            Some(previous.get_type())
-        } else {
-            None
-        };
-
-        self.parse_precedence(Precedence::Unary);
+    fn unary(&mut self, _can_assign: bool) {
+        if let Some(operator) = self.previous.clone() {
+            self.parse_precedence(Precedence::Unary);
 
-        match operator_type {
-            Some(TokenType::Minus) => self.emit_byte(OpCode::OpNegate as u8),
-            Some(TokenType::Bang) => self.emit_byte(OpCode::OpNot as u8),
-            None => self.error_at_current("No unary operator found.".to_string()),
-            _ => return,
+            match operator.get_type() {
+                TokenType::Minus => self.emit_byte_at(OpCode::OpNegate as u8, &operator),
+                // Only postfix `--` decrements, as a prefix it negates twice
+                TokenType::MinusMinus => {
+                    self.emit_byte_at(OpCode::OpNegate as u8, &operator);
+                    self.emit_byte_at(OpCode::OpNegate as u8, &operator);
+                }
+                TokenType::Bang => self.emit_byte_at(OpCode::OpNot as u8, &operator),
+                TokenType::Typeof => self.emit_byte_at(OpCode::OpType as u8, &operator),
+                _ => return,
+            }
+        } else {
+            self.error_at_current("No unary operator found.".to_string());
         }
     }
 
-    fn binary(&mut self) {
-        if let Some(operator) = &self.previous {
+    fn binary(&mut self, _can_assign: bool) {
+        if let Some(operator) = self.previous.clone() {
             let operator_type = operator.get_type();
             let rule = self.get_rule(&operator_type);
 
@@ -221,27 +1040,146 @@ impl Compiler {
                 Err(message) => self.error_at_current(message),
             }
 
-            match operator_type {
-                TokenType::Plus => self.emit_byte(OpCode::OpAdd as u8),
-                TokenType::Minus => self.emit_byte(OpCode::OpSubtract as u8),
-                TokenType::Star => self.emit_byte(OpCode::OpMultiply as u8),
-                TokenType::Slash => self.emit_byte(OpCode::OpDivide as u8),
-                TokenType::BangEqual => self.emit_bytes(OpCode::OpEqual as u8, OpCode::OpNot as u8),
-                TokenType::EqualEqual => self.emit_byte(OpCode::OpEqual as u8),
-                TokenType::Greater => self.emit_byte(OpCode::OpGreater as u8),
-                TokenType::GreaterEqual => {
-                    self.emit_bytes(OpCode::OpLess as u8, OpCode::OpNot as u8)
+            // Attributed to the operator so runtime errors point at it
+            let instructions: &[OpCode] = match operator_type {
+                TokenType::Plus => &[OpCode::OpAdd],
+                TokenType::Minus => &[OpCode::OpSubtract],
+                TokenType::Star => &[OpCode::OpMultiply],
+                TokenType::Slash => &[OpCode::OpDivide],
+                TokenType::Percent => &[OpCode::OpModulo],
+                TokenType::BangEqual => &[OpCode::OpEqual, OpCode::OpNot],
+                TokenType::EqualEqual => &[OpCode::OpEqual],
+                TokenType::Greater => &[OpCode::OpGreater],
+                TokenType::GreaterEqual => &[OpCode::OpLess, OpCode::OpNot],
+                TokenType::GreaterGreater => &[OpCode::OpShiftRight],
+                TokenType::GreaterGreaterGreater => &[OpCode::OpShiftRightLogical],
+                TokenType::Less => &[OpCode::OpLess],
+                TokenType::LessLess => &[OpCode::OpShiftLeft],
+                TokenType::DotDot => &[OpCode::OpRange],
+                TokenType::DotDotEqual => &[OpCode::OpRangeInclusive],
+                TokenType::LessEqual => &[OpCode::OpGreater, OpCode::OpNot],
+                TokenType::In => &[OpCode::OpContains],
+                TokenType::Is => &[OpCode::OpIs],
+                _ => return,
+            };
+
+            for instruction in instructions {
+                self.emit_byte_at(*instruction as u8, &operator);
+            }
+        }
+    }
+
+    // `a ?? b` keeps `a` unless it is nil, `b` is only evaluated otherwise.
+    fn coalesce(&mut self, _can_assign: bool) {
+        let nil_jump = self.emit_jump(OpCode::OpJumpIfNil);
+        let end_jump = self.emit_jump(OpCode::OpJump);
+
+        self.patch_jump(nil_jump);
+        self.emit_byte(OpCode::OpPop as u8);
+
+        self.parse_precedence(Precedence::And);
+        self.patch_jump(end_jump);
+    }
+
+    fn lambda(&mut self, _can_assign: bool) {
+        self.function(None, FunctionType::Function);
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        if let Some(paren) = self.previous.clone() {
+            let native_callee = self.native_callee;
+            let native = native_callee == Some(self.current_chunk_len());
+            let arg_count = self.argument_list();
+            if native {
+                self.emit_byte_at(OpCode::OpCallNative as u8, &paren);
+            } else {
+                self.last_call = Some(self.current_chunk_len());
+                self.emit_byte_at(OpCode::OpCall as u8, &paren);
+            }
+            self.emit_byte(arg_count);
+        }
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX as usize {
+                    self.error("Can't have more than 255 arguments.".to_string());
                 }
-                TokenType::Less => self.emit_byte(OpCode::OpLess as u8),
-                TokenType::LessEqual => {
-                    self.emit_bytes(OpCode::OpGreater as u8, OpCode::OpNot as u8)
+                arg_count += 1;
+
+                if !self.match_token(TokenType::Comma) {
+                    break;
                 }
-                _ => return,
+            }
+        }
+
+        self.consume(
+            TokenType::RightParen,
+            "Expect ')' after arguments.".to_string(),
+        );
+        return arg_count.min(u8::MAX as usize) as u8;
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        if let Some(name) = self.previous.clone() {
+            self.named_variable(name, can_assign);
+        }
+    }
+
+    fn named_variable(&mut self, name: Token, can_assign: bool) {
+        let lexeme = name.get_lexeme();
+        let (get_op, set_op, arg) = match self.resolve_local(&lexeme) {
+            Some(slot) => (OpCode::OpGetLocal, OpCode::OpSetLocal, slot as usize),
+            None => (
+                OpCode::OpGetGlobal,
+                OpCode::OpSetGlobal,
+                self.identifier_constant(lexeme.clone()),
+            ),
+        };
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_variable(set_op, arg);
+        } else if self.check(TokenType::PlusPlus) || self.check(TokenType::MinusMinus) {
+            self.advance();
+            self.postfix_update(get_op, set_op, arg);
+        } else {
+            self.emit_variable(get_op, arg);
+            if matches!(get_op, OpCode::OpGetGlobal) && is_native(&lexeme) {
+                self.native_callee = Some(self.current_chunk_len());
+            }
+        }
+    }
+
+    // Postfix `++`/`--`, the expression keeps the value from before the update.
+    fn postfix_update(&mut self, get_op: OpCode, set_op: OpCode, arg: usize) {
+        let increment = self
+            .previous
+            .as_ref()
+            .is_some_and(|previous| previous.get_type() == TokenType::PlusPlus);
+
+        self.emit_variable(get_op, arg);
+        match (get_op, increment) {
+            (OpCode::OpGetLocal, true) => self.emit_bytes(OpCode::OpIncrement as u8, arg as u8),
+            (OpCode::OpGetLocal, false) => self.emit_bytes(OpCode::OpDecrement as u8, arg as u8),
+            (_, increment) => {
+                // Globals have no in place update
+                self.emit_variable(get_op, arg);
+                self.emit_byte(OpCode::OpOne as u8);
+                match increment {
+                    true => self.emit_byte(OpCode::OpAdd as u8),
+                    false => self.emit_byte(OpCode::OpSubtract as u8),
+                }
+                self.emit_variable(set_op, arg);
+                self.emit_byte(OpCode::OpPop as u8);
             }
         }
     }
 
-    fn literal(&mut self) {
+    fn literal(&mut self, _can_assign: bool) {
         if let Some(previous) = &self.previous {
             match previous.get_type() {
                 TokenType::False => self.emit_byte(OpCode::OpFalse as u8),
@@ -252,7 +1190,25 @@ impl Compiler {
         }
     }
 
+    // Counts one more level of nesting, or reports `message`, skips the rest
+    // of the input and returns false when that would pass the limit.
+    fn enter_nesting(&mut self, message: &str) -> bool {
+        if self.nesting_depth >= self.max_nesting_depth {
+            self.error_at_current(message.to_string());
+            self.nesting_overflowed = true;
+            while !self.check(TokenType::EOF) {
+                self.advance();
+            }
+            return false;
+        }
+        self.nesting_depth += 1;
+        return true;
+    }
+
     fn parse_precedence(&mut self, precedence: Precedence) {
+        if !self.enter_nesting("Expression nesting too deep.") {
+            return;
+        }
         self.advance();
 
         if let Some(previous) = &self.previous {
@@ -263,10 +1219,15 @@ impl Compiler {
                     infix: _,
                     precedence: _,
                 } => {
-                    prefix_rule(self);
+                    let can_assign = !precedence.is_higher_than(Precedence::Assignment);
+                    prefix_rule(self, can_assign);
 
                     while let Some(current) = &self.current {
-                        if precedence > self.get_rule(&current.get_type()).precedence {
+                        if self
+                            .get_rule(&current.get_type())
+                            .precedence
+                            .is_lower_than(precedence)
+                        {
                             break;
                         }
                         self.advance();
@@ -279,16 +1240,30 @@ impl Compiler {
                                     infix: Some(infix_rule),
                                     precedence: _,
                                 } => {
-                                    infix_rule(self);
+                                    infix_rule(self, can_assign);
                                 }
                                 _ => self.error_at_current("Expect expression.".to_string()),
                             }
                         }
                     }
+
+                    if can_assign && self.match_token(TokenType::Equal) {
+                        self.error("Invalid assignment target.".to_string());
+                    }
                 }
                 _ => self.error_at_current("Expect expression.".to_string()),
             };
         }
+        self.nesting_depth -= 1;
+    }
+
+    fn report_progress(&mut self, at_end: bool) {
+        self.token_count += 1;
+        if let Some(callback) = &self.progress_callback.0 {
+            if at_end || self.token_count.is_multiple_of(PROGRESS_INTERVAL) {
+                callback(self.scanner.progress());
+            }
+        }
     }
 
     fn advance(&mut self) {
@@ -298,6 +1273,14 @@ impl Compiler {
             self.current = Some(self.scanner.scan_token());
             if let Some(current) = &self.current {
                 if current.get_type() != TokenType::Error {
+                    // Parsing can advance past EOF more than once, the end is
+                    // only reported the first time.
+                    let at_end = current.get_type() == TokenType::EOF
+                        && !self
+                            .previous
+                            .as_ref()
+                            .is_some_and(|previous| previous.get_type() == TokenType::EOF);
+                    self.report_progress(at_end);
                     break;
                 }
 
@@ -307,16 +1290,30 @@ impl Compiler {
     }
 
     fn consume(&mut self, ttype: TokenType, message: String) {
-        if let Some(current) = &self.current {
-            if current.get_type() == ttype {
-                self.advance();
-                return;
-            }
+        if self.match_token(ttype) {
+            return;
         }
 
         self.error_at_current(message);
     }
 
+    fn check(&self, ttype: TokenType) -> bool {
+        return self
+            .current
+            .as_ref()
+            .map(|current| current.get_type() == ttype)
+            .unwrap_or(false);
+    }
+
+    fn match_token(&mut self, ttype: TokenType) -> bool {
+        if !self.check(ttype) {
+            return false;
+        }
+
+        self.advance();
+        return true;
+    }
+
     fn get_rule(&self, ttype: &TokenType) -> &ParseRule {
         if let Some(rule) = RULES.get(*ttype as usize) {
             return rule;
@@ -325,23 +1322,26 @@ impl Compiler {
         }
     }
 
+    fn current_chunk(&self) -> Option<&Chunk> {
+        return self.compiling_chunk.as_ref();
+    }
+
+    fn current_chunk_mut(&mut self) -> Option<&mut Chunk> {
+        return self.compiling_chunk.as_mut();
+    }
+
     fn emit_byte(&mut self, byte: u8) {
-        if let Some(previous) = &self.previous {
-            match (self.compiling_chunk.take(), self.compiling_file.take()) {
-                (Some(mut chunk), None) => {
-                    chunk.write_byte(byte, previous.get_line());
-                    self.compiling_chunk = Some(chunk);
-                }
-                (None, Some(mut file)) => {
-                    let contents = [byte, previous.get_line() as u8];
-                    match file.write_all(&contents) {
-                        Err(error) => self.error_at_current(error.to_string()),
-                        _ => (),
-                    };
-                    self.compiling_file = Some(file);
-                }
-                _ => {}
-            }
+        if let Some(previous) = self.previous.clone() {
+            self.emit_byte_at(byte, &previous);
+        }
+    }
+
+    // Emits `byte` attributed to the source position of `token`.
+    fn emit_byte_at(&mut self, byte: u8, token: &Token) {
+        // Only a call straight after the native's name may use OpCallNative
+        self.native_callee = None;
+        if let Some(chunk) = self.current_chunk_mut() {
+            chunk.write_byte_at(byte, token.get_line(), token.get_column());
         }
     }
 
@@ -350,30 +1350,84 @@ impl Compiler {
         self.emit_byte(byte_2);
     }
 
+    fn emit_loop(&mut self, loop_start: usize) {
+        // +3 to also jump back over the OpLoop instruction and its offset
+        let offset = self.current_chunk_len() - loop_start + 3;
+        if offset > u16::MAX as usize {
+            self.error_at_current("Loop body too large.".to_string());
+        }
+
+        self.emit_byte(OpCode::OpLoop as u8);
+        self.emit_bytes(((offset >> 8) & 0xff) as u8, (offset & 0xff) as u8);
+    }
+
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_byte(instruction as u8);
+        self.emit_bytes(0xff, 0xff);
+        return self.current_chunk_len() - 2;
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the jump offset itself
+        let jump = self.current_chunk_len() - offset - 2;
+
+        if jump > u16::MAX as usize {
+            self.error_at_current("Too much code to jump over.".to_string());
+            return;
+        }
+
+        self.patch_byte(offset, ((jump >> 8) & 0xff) as u8);
+        self.patch_byte(offset + 1, (jump & 0xff) as u8);
+    }
+
+    fn current_chunk_len(&self) -> usize {
+        return self.current_chunk().map_or(0, Chunk::code_len);
+    }
+
+    fn patch_byte(&mut self, offset: usize, byte: u8) {
+        if let Some(chunk) = self.current_chunk_mut() {
+            chunk.patch_byte(offset, byte);
+        }
+    }
+
     fn emit_return(&mut self) {
         self.emit_byte(OpCode::OpReturn as u8);
     }
 
-    fn emit_constant(&mut self, value: Value) {
-        match self.make_constant(value) {
-            Ok(constant) => self.emit_bytes(OpCode::OpConstant as u8, constant),
-            Err(err) => self.error_at_current(err),
+    // What falling off the end of a function or a bare `return` gives back.
+    fn emit_implicit_return(&mut self) {
+        match self.function_type {
+            FunctionType::Initializer => {
+                self.emit_bytes(OpCode::OpGetLocal as u8, 0);
+                self.emit_return();
+            }
+            _ => self.emit_return_nil(),
         }
     }
 
-    fn make_constant(&mut self, mut value: Value) -> Result<u8, String> {
-        if let Some(mut chunk) = self.compiling_chunk.take() {
-            let constant = chunk.add_constant(value);
-            self.compiling_chunk = Some(chunk);
-            return Ok(constant);
+    // Function bodies always leave a value for the caller, unlike the top
+    // level script.
+    fn emit_return_nil(&mut self) {
+        self.emit_byte(OpCode::OpNil as u8);
+        self.emit_return();
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        let token = self.previous.clone();
+        if let (Some(chunk), Some(token)) = (self.current_chunk_mut(), token) {
+            let written = chunk.write_constant_long_at(value, token.get_line(), token.get_column());
+            if let Err(err) = written {
+                self.error_at_current(err);
+            }
         }
+    }
 
-        if let Some(_) = &self.compiling_file {
-            if value.is_number() {
-                return Ok(value.as_number() as u8);
-            } else {
-                return Err(format!("Invalid constant found: {:?}", value));
+    fn make_constant(&mut self, value: Value) -> Result<usize, String> {
+        if let Some(chunk) = self.current_chunk_mut() {
+            if chunk.constants.len() > MAX_LONG_CONSTANT {
+                return Err("Too many constants in one chunk.".to_string());
             }
+            return Ok(chunk.push_constant(value));
         }
 
         return Err("No compiling chunk available.".to_string());
@@ -382,33 +1436,468 @@ impl Compiler {
     fn end(&mut self) {
         self.emit_return();
 
-        if DEBUG_PRINT_CODE && !self.had_error {
-            if let Some(chunk) = &self.compiling_chunk {
-                let _ = chunk.dissasemble("code");
+        if DEBUG_PRINT_CODE && self.errors.is_empty() {
+            // Listed as a function so the ones nested in it are listed too,
+            // the chunk is moved back once printed.
+            if let Some(chunk) = self.compiling_chunk.take() {
+                let script = ObjFunction {
+                    arity: 0,
+                    min_arity: 0,
+                    entry_points: vec![0],
+                    upvalue_count: 0,
+                    chunk: Rc::new(chunk),
+                    name: Some("code".to_string()),
+                };
+                if let Ok(listing) = disassemble_function(&script) {
+                    print!("{}", listing);
+                }
+                self.compiling_chunk = Rc::into_inner(script.chunk);
             }
         }
     }
 
+    fn error(&mut self, message: String) {
+        if let Some(previous) = self.previous.clone() {
+            self.error_at(previous, message, None);
+        }
+    }
+
     fn error_at_current(&mut self, message: String) {
+        // Building the context scans the rest of the line, skip it when the
+        // error would be dropped anyway.
+        if self.panic_mode {
+            return;
+        }
         if let Some(current) = self.current.clone() {
-            self.error_at(current, message);
+            let lexeme = match current.get_type() {
+                TokenType::EOF | TokenType::Error => String::new(),
+                _ => current.get_lexeme(),
+            };
+            let context = format!("{}{}", lexeme, self.scanner.current_line_remaining());
+            self.error_at(current, message, Some(context));
         }
     }
 
-    fn error_at(&mut self, token: Token, message: String) {
+    fn error_at(&mut self, token: Token, message: String, context: Option<String>) {
         if self.panic_mode {
             return;
         }
+        self.panic_mode = true;
 
-        print!("[Line {}] Error", token.get_line());
-
-        match token.get_type() {
-            TokenType::EOF => print!(" at end"),
-            TokenType::Error => (),
-            _ => print!(" at '{}'", token.get_lexeme()),
+        let token_lexeme = match token.get_type() {
+            TokenType::EOF => Some(String::new()),
+            TokenType::Error => None,
+            _ => Some(token.get_lexeme()),
         };
+        self.errors.push(CompileError {
+            line: token.get_line(),
+            column: token.get_column(),
+            message,
+            token_lexeme,
+            context,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::Write;
+
+    use crate::lox::Lox;
+    use crate::vm::Vm;
+
+    // Collects what `print` writes so tests can compare it.
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            return self.0.borrow_mut().write(buf);
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    fn run_chunk(chunk: Chunk) -> String {
+        let output = SharedOutput::default();
+        Vm::new()
+            .with_output(output.clone())
+            .interpret_chunk(chunk)
+            .unwrap();
+        return String::from_utf8(output.0.take()).unwrap();
+    }
+
+    fn temp_dir(name: &str) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("lox-compiler-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        return dir.to_string_lossy().into_owned();
+    }
+
+    fn function_constant(chunk: &Chunk, name: &str) -> Rc<Chunk> {
+        for constant in &chunk.constants {
+            if let Some(Obj::ObjFunction(function)) = constant.as_obj().map(|obj| &**obj) {
+                if function.display_name() == name {
+                    return Rc::clone(&function.chunk);
+                }
+            }
+        }
+        panic!("No function '{}' in the chunk.", name);
+    }
+
+    fn opcodes(chunk: &Chunk) -> Vec<u8> {
+        return chunk
+            .instructions()
+            .unwrap()
+            .into_iter()
+            .map(|(_, instruction)| instruction as u8)
+            .collect();
+    }
+
+    const FRAME: [u8; 2] = [OpCode::OpPushFrame as u8, OpCode::OpPopFrame as u8];
+
+    #[test]
+    fn block_without_locals_has_no_scope_frame() {
+        let chunk = compile("{ print 1; } { { print 2; } }").unwrap();
+        assert!(!opcodes(&chunk).iter().any(|op| FRAME.contains(op)));
+    }
+
+    #[test]
+    fn block_locals_are_popped_with_frames_or_counted_pops() {
+        let chunk = compile("{ var a = 1; var b = 2; print a + b; }").unwrap();
+        let ops = opcodes(&chunk);
+        let framed = ops.iter().filter(|op| FRAME.contains(op)).count();
+        let counted = ops.contains(&(OpCode::OpPopN as u8));
+        match SCOPE_FRAMES {
+            true => assert!(framed == 2 && !counted),
+            false => assert!(framed == 0 && counted),
+        }
+        assert_eq!(run_chunk(chunk), "3\n");
+    }
+
+    #[test]
+    fn function_locals_have_no_scope_frame() {
+        let source = "{ fun f(a) { var b = a; return b; } print f(4); }";
+        let chunk = compile(source).unwrap();
+        let function = function_constant(&chunk, "f");
+        assert!(!opcodes(&function).iter().any(|op| FRAME.contains(op)));
+        assert_eq!(run_chunk(chunk), "4\n");
+    }
+
+    #[test]
+    fn local_cant_read_itself_in_its_initializer() {
+        for source in ["{ var x = x; }", "{ var x = 1; { var x = x + 1; } }"] {
+            let errors = compile(source).unwrap_err();
+            assert_eq!(errors.len(), 1);
+            assert_eq!(
+                errors[0].message,
+                "Cannot read local variable in its own initializer."
+            );
+        }
+        // Globals are looked up when the code runs
+        assert!(compile("var x = x;").is_ok());
+        assert_eq!(
+            run_chunk(compile("{ var x = 1; var y = x; print y; }").unwrap()),
+            "1\n"
+        );
+    }
+
+    #[test]
+    fn uninitialized_var_is_nil() {
+        assert_eq!(run_chunk(compile("var a; print a;").unwrap()), "nil\n");
+        assert_eq!(run_chunk(compile("{ var a; print a; }").unwrap()), "nil\n");
+    }
+
+    #[test]
+    fn redeclaring_a_local_in_the_same_scope_is_an_error() {
+        let errors = compile("{ var a = 1; var a = 2; }").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].message,
+            "Already a variable with this name in this scope."
+        );
+
+        assert!(compile("{ var a = 1; { var a = 2; } }").is_ok());
+        assert!(compile("var a = 1; var a = 2;").is_ok());
+    }
+
+    #[test]
+    fn every_statement_reports_its_own_error() {
+        let errors = compile("var = 1;\nprint 2;\nvar 3;").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!((errors[0].line, errors[1].line), (1, 3));
+        assert_eq!(errors[0].token_lexeme.as_deref(), Some("="));
+        assert_eq!(errors[1].message, "Expect variable name.");
+    }
+
+    #[test]
+    fn lambda_is_a_value_that_can_be_passed_and_called() {
+        let source = "fun map2(f, a, b) { print f(a); print f(b); }
+            map2(fun (x) { return x * 10; }, 1, 2);
+            var inc = fun (x) { return x + 1; };
+            print inc(5);";
+        assert_eq!(run_chunk(compile(source).unwrap()), "10\n20\n6\n");
+    }
+
+    #[test]
+    fn omitted_arguments_take_their_default() {
+        let source = "fun greet(name, greeting = \"hi\", mark = \"!\") {
+                print greeting;
+                print name;
+                print mark;
+            }
+            greet(\"a\");
+            greet(\"b\", \"hello\");
+            greet(\"c\", \"hey\", \"?\");";
+        assert_eq!(
+            run_chunk(compile(source).unwrap()),
+            "hi\na\n!\nhello\nb\n!\nhey\nc\n?\n"
+        );
+    }
+
+    #[test]
+    fn required_parameter_after_an_optional_one_is_an_error() {
+        let errors = compile("fun f(a = 1, b) {}").unwrap_err();
+        assert_eq!(
+            errors[0].message,
+            "Expect default value after an optional parameter."
+        );
+    }
+
+    #[test]
+    fn tail_call_in_earlier_function_does_not_patch_later_ones() {
+        let source = "fun f() { return 0; } f(); fun g() { return 2 + 3 + 4; }";
+        let chunk = compile(source).unwrap();
+        let g = function_constant(&chunk, "g");
+        assert!(chunk.verify().is_ok());
+        assert!(!opcodes(&g).contains(&(OpCode::OpTailCall as u8)));
+
+        let mut lox = Lox::new();
+        lox.run(source).unwrap();
+        assert_eq!(lox.eval("g()").unwrap().as_number(), 9.0);
+    }
+
+    #[test]
+    fn call_before_function_is_not_a_tail_call_inside_it() {
+        let chunk = compile("fun f() {} f(); fun g() { return 1; }").unwrap();
+        let g = function_constant(&chunk, "g");
+        assert!(!opcodes(&g).contains(&(OpCode::OpTailCall as u8)));
+    }
+
+    #[test]
+    fn returned_call_is_a_tail_call() {
+        let chunk = compile("fun f(n) { return f(n); }").unwrap();
+        let f = function_constant(&chunk, "f");
+        assert!(opcodes(&f).contains(&(OpCode::OpTailCall as u8)));
+
+        let chunk = compile("fun f(n) { return f(n) + 1; }").unwrap();
+        let f = function_constant(&chunk, "f");
+        assert!(!opcodes(&f).contains(&(OpCode::OpTailCall as u8)));
+    }
+
+    #[test]
+    fn deep_tail_recursion_does_not_overflow_frames() {
+        let mut lox = Lox::new();
+        lox.run("fun count(n) { if (n == 0) return 0; return count(n - 1); }")
+            .unwrap();
+        assert_eq!(lox.eval("count(100000)").unwrap().as_number(), 0.0);
+    }
+
+    #[test]
+    fn number_literal_out_of_range_is_an_error() {
+        // There is no exponent syntax, this is 1e400 spelled out.
+        let errors = compile(&format!("print 1{};", "0".repeat(400))).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Number literal out of range.");
+
+        let chunk = compile(&format!("print 1{};", "0".repeat(300))).unwrap();
+        assert_eq!(chunk.constants.len(), 1);
+        assert_eq!(chunk.constants[0].as_number(), 1e300);
+    }
+
+    #[test]
+    fn compile_cached_hits_the_cache_the_second_time() {
+        let dir = temp_dir("hit");
+        let source = "print 1 + 2;";
+        let (_, hit) = Compiler::compile_cached(source, &dir).unwrap();
+        assert!(!hit);
+        let (chunk, hit) = Compiler::compile_cached(source, &dir).unwrap();
+        assert!(hit);
+        assert_eq!(run_chunk(chunk), "3\n");
+
+        let (_, hit) = Compiler::compile_cached("print 1 + 3;", &dir).unwrap();
+        assert!(!hit);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn cached_chunk_runs_like_a_fresh_one() {
+        let dir = temp_dir("same");
+        let source = "var x = 3.75; fun f(s) { return s; } print x, -300, 70000, f(\"hi\");";
+        let fresh = run_chunk(compile(source).unwrap());
+        assert_eq!(fresh, "3.75 -300 70000 hi\n");
+
+        Compiler::compile_cached(source, &dir).unwrap();
+        let (chunk, hit) = Compiler::compile_cached(source, &dir).unwrap();
+        assert!(hit);
+        assert_eq!(run_chunk(chunk), fresh);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn to_file_keeps_globals_strings_and_functions() {
+        let dir = temp_dir("file");
+        fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/program", dir);
+        let source = "var greeting = \"hi\"; fun f(n) { return n * 2; } print greeting, f(21);";
+        Compiler::new(source.to_string()).to_file(&path).unwrap();
+
+        let chunk = Chunk::from_bin(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(run_chunk(chunk), "hi 42\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // Compiles `source` as the parameters and body of a function of
+    // `function_type`, the language can't declare methods or initializers
+    // yet. Returns the error messages and the function's chunk.
+    fn compile_function(source: &str, function_type: FunctionType) -> (Vec<String>, Rc<Chunk>) {
+        let mut compiler = Compiler::new(source.to_string());
+        compiler.compiling_chunk = Some(Chunk::new());
+        compiler.advance();
+        compiler.function(Some("f".to_string()), function_type);
+
+        let messages = compiler.errors.iter().map(|error| error.message.clone());
+        let chunk = compiler.compiling_chunk.take().unwrap();
+        return (messages.collect(), function_constant(&chunk, "f"));
+    }
+
+    #[test]
+    fn script_cant_return() {
+        match compile("return 1;") {
+            Err(errors) => assert_eq!(errors[0].message, "Can't return from top-level code."),
+            Ok(_) => panic!("Expected a compile error."),
+        }
+    }
+
+    #[test]
+    fn functions_and_methods_return_values() {
+        for function_type in [FunctionType::Function, FunctionType::Method] {
+            let (errors, _) = compile_function("(a) { return a; }", function_type);
+            assert!(errors.is_empty(), "{:?}", errors);
+        }
+    }
+
+    #[test]
+    fn initializer_cant_return_a_value() {
+        let (errors, _) = compile_function("() { return 1; }", FunctionType::Initializer);
+        assert_eq!(errors, ["Can't return a value from an initializer."]);
+    }
+
+    #[test]
+    fn initializer_returns_slot_zero() {
+        let (errors, chunk) = compile_function("(a) { if (a) return; }", FunctionType::Initializer);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let get_this = [OpCode::OpGetLocal as u8, 0, OpCode::OpReturn as u8];
+        let code: Vec<u8> = (0..chunk.code_len())
+            .filter_map(|i| chunk.byte_at(i))
+            .collect();
+        // Both the bare `return` and falling off the end give back the instance
+        assert_eq!(
+            code.windows(3).filter(|window| *window == get_this).count(),
+            2
+        );
+        assert!(code.ends_with(&get_this));
+    }
+
+    fn nesting_errors(source: &str) -> Vec<String> {
+        let errors = compile(source).unwrap_err();
+        return errors.into_iter().map(|error| error.message).collect();
+    }
+
+    #[test]
+    fn deeply_nested_parentheses_are_an_error() {
+        let source = format!("print {}1{};", "(".repeat(100_000), ")".repeat(100_000));
+        assert_eq!(nesting_errors(&source), ["Expression nesting too deep."]);
+
+        let depth = MAX_NESTING_DEPTH / 2;
+        let source = format!("print {}1{};", "(".repeat(depth), ")".repeat(depth));
+        assert!(compile(&source).is_ok());
+    }
+
+    #[test]
+    fn deeply_nested_statements_are_an_error() {
+        let source = format!("{}{}", "{".repeat(200_000), "}".repeat(200_000));
+        assert_eq!(nesting_errors(&source), ["Statement nesting too deep."]);
+
+        let depth = MAX_NESTING_DEPTH - 1;
+        assert!(compile(&format!("{}{}", "{".repeat(depth), "}".repeat(depth))).is_ok());
+
+        // Whichever of the condition or the body hits the limit first
+        let source = format!("{}print 1;", "if (true) ".repeat(200_000));
+        let errors = nesting_errors(&source);
+        assert!(errors.len() == 1 && errors[0].ends_with("nesting too deep."));
+
+        let source = format!("{}{}", "fun f() {".repeat(200_000), "}".repeat(200_000));
+        let errors = nesting_errors(&source);
+        assert!(errors.len() == 1 && errors[0].ends_with("nesting too deep."));
+    }
+
+    #[test]
+    fn nesting_limit_is_configurable() {
+        let mut compiler = Compiler::new("print ((1));".to_string()).with_max_nesting_depth(3);
+        let errors = compiler.to_chunk(Chunk::new()).unwrap_err();
+        assert_eq!(errors[0].message, "Expression nesting too deep.");
+    }
+
+    #[test]
+    fn native_call_compiles_to_call_native() {
+        let chunk = compile("sqrt(4);").unwrap();
+        assert!(opcodes(&chunk).contains(&(OpCode::OpCallNative as u8)));
+        assert!(!opcodes(&chunk).contains(&(OpCode::OpCall as u8)));
+
+        let mut lox = Lox::new();
+        assert_eq!(lox.eval("sqrt(16)").unwrap().as_number(), 4.0);
+        assert!(lox.eval("sqrt(16, 2)").is_err());
+    }
+
+    #[test]
+    fn native_named_in_a_lambda_does_not_leak_into_the_call() {
+        let chunk = compile("(fun() { sqrt; })();").unwrap();
+        assert!(opcodes(&chunk).contains(&(OpCode::OpCall as u8)));
+        assert!(!opcodes(&chunk).contains(&(OpCode::OpCallNative as u8)));
+
+        let chunk = compile("fun f() { return (fun() { sqrt; })(); }").unwrap();
+        let f = function_constant(&chunk, "f");
+        assert!(opcodes(&f).contains(&(OpCode::OpTailCall as u8)));
+    }
+
+    #[test]
+    fn native_value_used_before_a_call_is_a_plain_call() {
+        let chunk = compile("var f = sqrt; f(4);").unwrap();
+        assert!(!opcodes(&chunk).contains(&(OpCode::OpCallNative as u8)));
+    }
+
+    #[test]
+    fn compile_returns_a_chunk_or_the_errors() {
+        let chunk = compile("1+2;").unwrap();
+        assert_eq!(
+            opcodes(&chunk),
+            [
+                OpCode::OpOne as u8,
+                OpCode::OpConstant as u8,
+                OpCode::OpAdd as u8,
+                OpCode::OpPop as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
 
-        println!(": {}", message);
-        self.had_error = true;
+        let errors = compile("1+").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Expect expression.");
     }
 }
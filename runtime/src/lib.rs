@@ -0,0 +1,10 @@
+pub mod common;
+
+pub mod compiler;
+pub mod scanner;
+
+pub mod chunk;
+pub mod lox;
+pub mod object;
+pub mod value;
+pub mod vm;
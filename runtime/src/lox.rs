@@ -0,0 +1,114 @@
+use std::fmt;
+use std::io::Write;
+
+use crate::chunk::Chunk;
+use crate::compiler::{CompileError, Compiler};
+use crate::value::{NumberFormat, Value};
+use crate::vm::{InterpretResult, RuntimeError, StepResult, Vm};
+
+#[derive(Debug)]
+pub enum LoxError {
+    Compile(Vec<CompileError>),
+    // Runtime errors are printed by the vm as they happen.
+    Interpret(InterpretResult),
+}
+
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoxError::Compile(errors) => {
+                let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+                return write!(f, "{}", errors.join("\n"));
+            }
+            LoxError::Interpret(result) => return write!(f, "{}", result),
+        }
+    }
+}
+
+impl std::error::Error for LoxError {}
+
+// Entry point for embedders, compiles and runs source without exposing the
+// compiler and vm. Globals persist between runs.
+#[derive(Debug)]
+pub struct Lox {
+    vm: Vm,
+    compiler: Compiler,
+}
+
+impl Default for Lox {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+impl Lox {
+    pub fn new() -> Self {
+        Self {
+            vm: Vm::new(),
+            compiler: Compiler::new(String::new()),
+        }
+    }
+
+    pub fn with_stdlib(mut self, stdlib: bool) -> Self {
+        self.vm = self.vm.with_stdlib(stdlib);
+        return self;
+    }
+
+    pub fn with_max_instructions(mut self, max_instructions: u64) -> Self {
+        self.vm = self.vm.with_max_instructions(max_instructions);
+        return self;
+    }
+
+    pub fn with_output(mut self, output: impl Write + 'static) -> Self {
+        self.vm = self.vm.with_output(output);
+        return self;
+    }
+
+    pub fn with_number_format(mut self, number_format: NumberFormat) -> Self {
+        self.vm.set_number_format(number_format);
+        return self;
+    }
+
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        self.vm.define_global(name, value);
+    }
+
+    // Names of the global Lox functions defined so far, sorted.
+    pub fn global_functions(&self) -> Vec<String> {
+        return self.vm.global_functions();
+    }
+
+    // Runtime error of the last run that compiled, `None` if it had none.
+    pub fn last_error(&self) -> Option<&RuntimeError> {
+        return self.vm.last_error();
+    }
+
+    // Forgets globals defined by earlier runs, natives stay.
+    pub fn reset(&mut self) {
+        self.vm.reset(true);
+    }
+
+    pub fn run(&mut self, source: &str) -> Result<(), LoxError> {
+        self.compiler.reset(source.to_string());
+        match self.compiler.to_chunk(Chunk::new()) {
+            Ok(chunk) => return self.vm.interpret_chunk(chunk).map_err(LoxError::Interpret),
+            Err(errors) => return Err(LoxError::Compile(errors)),
+        }
+    }
+
+    // `source` is a single expression, like `1 + 2` or `sqrt(x)`.
+    pub fn eval(&mut self, source: &str) -> Result<Value, LoxError> {
+        self.compiler.reset(source.to_string());
+        let chunk = self
+            .compiler
+            .to_expression_chunk(Chunk::new())
+            .map_err(LoxError::Compile)?;
+
+        self.vm.load_chunk(chunk).map_err(LoxError::Interpret)?;
+        loop {
+            if let StepResult::Returned(value) = self.vm.run().map_err(LoxError::Interpret)? {
+                return Ok(value);
+            }
+        }
+    }
+}
@@ -1,24 +1,22 @@
-mod common;
-
-mod compiler;
-mod scanner;
-
-mod chunk;
-mod value;
-mod vm;
-
-use compiler::Compiler;
-use vm::{InterpretResult, Vm};
+use runtime::chunk::Chunk;
+use runtime::common;
+use runtime::compiler::{CompileError, Compiler};
+use runtime::lox::{Lox, LoxError};
+use runtime::scanner::{Scanner, TokenType};
+use runtime::value::NumberFormat;
+use runtime::vm::{InterpretResult, RuntimeError, Vm};
 
 use std::{
     env, fs,
     io::{self, BufRead, Write},
+    time::{Duration, Instant},
 };
 
 fn repl() -> Result<(), String> {
+    let mut lox = Lox::new();
     loop {
         print!("> ");
-        if let Err(_) = io::stdout().flush() {
+        if io::stdout().flush().is_err() {
             return Err(common::repl_error("Failed to flush stdout".to_string()));
         }
 
@@ -26,7 +24,7 @@ fn repl() -> Result<(), String> {
         let mut handle = stdin.lock();
         let mut buffer = String::new();
 
-        if let Err(_) = handle.read_line(&mut buffer) {
+        if handle.read_line(&mut buffer).is_err() {
             return Err(common::repl_error("Failed to read from stdin".to_string()));
         }
 
@@ -34,17 +32,39 @@ fn repl() -> Result<(), String> {
             return Ok(());
         }
 
-        if let Err(_) = run_source(buffer) {
-            return Err(common::repl_error(
-                "Failed to run due to above error.".to_string(),
-            ));
+        if buffer.trim() == ".reset" {
+            lox.reset();
+            continue;
+        }
+
+        match lox.run(&buffer) {
+            Ok(()) => (),
+            Err(LoxError::Interpret(InterpretResult::InterpretExit(code))) => {
+                std::process::exit(code)
+            }
+            Err(error) => {
+                if let LoxError::Compile(errors) = &error {
+                    report_compile_errors(errors);
+                }
+                return Err(common::repl_error(
+                    "Failed to run due to above error.".to_string(),
+                ));
+            }
         }
     }
 }
 
-fn run_source(source: String) -> Result<(), InterpretResult> {
-    let mut vm = Vm::new();
-    vm.interpret_source(source)
+fn report_compile_errors(errors: &[CompileError]) {
+    for error in errors {
+        println!("{}", error);
+    }
+}
+
+// A script's `exit` ends the process with its code.
+fn exit_on_script_exit(error: &InterpretResult) {
+    if let InterpretResult::InterpretExit(code) = error {
+        std::process::exit(*code);
+    }
 }
 
 fn run_file(input_path: &str) -> Result<(), String> {
@@ -58,13 +78,14 @@ fn run_file(input_path: &str) -> Result<(), String> {
         Ok(source) => {
             if let Some(filename) = input_path
                 .split('/')
-                .last()
+                .next_back()
                 .and_then(|name| name.strip_suffix(".lox"))
             {
                 match compile_source(source, &format!("lox/bin/{}", filename)) {
                     Ok(op_code) => {
                         let mut vm = Vm::new();
-                        if let Err(_) = vm.interpret_op_code(op_code) {
+                        if let Err(error) = vm.interpret_op_code(op_code) {
+                            exit_on_script_exit(&error);
                             return Err(common::runtime_error(
                                 "Failed to run due to above error.".to_string(),
                             ));
@@ -74,7 +95,7 @@ fn run_file(input_path: &str) -> Result<(), String> {
                     Err(msg) => return Err(msg),
                 }
             }
-            return Err(common::runtime_error(format!("Invalid filename")));
+            return Err(common::runtime_error("Invalid filename".to_string()));
         }
     }
 }
@@ -89,7 +110,8 @@ fn run_bin(input_path: &str) -> Result<(), String> {
         }
         Ok(op_code) => {
             let mut vm = Vm::new();
-            if let Err(_) = vm.interpret_op_code(op_code) {
+            if let Err(error) = vm.interpret_op_code(op_code) {
+                exit_on_script_exit(&error);
                 return Err(common::runtime_error(
                     "Failed to run due to above error.".to_string(),
                 ));
@@ -99,7 +121,7 @@ fn run_bin(input_path: &str) -> Result<(), String> {
     }
 }
 
-fn compile_file(input_path: &str) -> Result<(), String> {
+fn compile_file(input_path: &str, strip_debug: bool) -> Result<(), String> {
     match fs::read_to_string(input_path) {
         Err(msg) => {
             return Err(common::runtime_error(format!(
@@ -110,14 +132,18 @@ fn compile_file(input_path: &str) -> Result<(), String> {
         Ok(source) => {
             if let Some(filename) = input_path
                 .split('/')
-                .last()
+                .next_back()
                 .and_then(|name| name.strip_suffix(".lox"))
             {
-                compile_source(source, &format!("lox/bin/{}", filename))?;
+                let output_path = format!("lox/bin/{}", filename);
+                compile_source(source, &output_path)?;
+                if strip_debug {
+                    strip_debug_info(&output_path)?;
+                }
                 println!("[DONE]: Successfully compiled to bin!");
                 return Ok(());
             }
-            return Err(common::runtime_error(format!("Invalid filename")));
+            return Err(common::runtime_error("Invalid filename".to_string()));
         }
     }
 }
@@ -137,6 +163,293 @@ fn compile_source(source: String, output_path: &str) -> Result<Vec<u8>, String>
     }
 }
 
+// Rewrites the bin at `path` without its line numbers.
+fn strip_debug_info(path: &str) -> Result<(), String> {
+    match fs::read(path) {
+        Err(msg) => {
+            return Err(common::runtime_error(format!(
+                "Failed to read bin at {}:\n\r{}",
+                path, msg
+            )))
+        }
+        Ok(op_code) => {
+            let chunk = Chunk::from_bin(&op_code)?.clone_without_debug_info();
+            if let Err(msg) = fs::write(path, chunk.to_bin()?) {
+                return Err(common::runtime_error(format!(
+                    "Failed to write bin at {}:\n\r{}",
+                    path, msg
+                )));
+            }
+            return Ok(());
+        }
+    }
+}
+
+struct TestReport {
+    passed: usize,
+    failed: usize,
+    errors: Vec<(String, RuntimeError)>,
+}
+
+impl TestReport {
+    fn record(&mut self, name: String, result: Result<(), RuntimeError>) {
+        match result {
+            Ok(()) => {
+                println!("[PASS]: {}", name);
+                self.passed += 1;
+            }
+            Err(error) => {
+                println!("[FAIL]: {}", name);
+                self.failed += 1;
+                self.errors.push((name, error));
+            }
+        }
+    }
+}
+
+// Runs every `.lox` file in `path` (or `path` itself). Once a file's top
+// level has run, each global function whose name starts with `test_` is
+// called as a test case, a file without any is one case itself. A case
+// fails on any error, like a failed `assert`.
+fn run_tests(path: &str) -> TestReport {
+    let mut report = TestReport {
+        passed: 0,
+        failed: 0,
+        errors: vec![],
+    };
+
+    let mut test_paths: Vec<String> = vec![];
+    match fs::read_dir(path) {
+        Ok(entries) => {
+            for entry in entries.flatten() {
+                let entry_path = entry.path();
+                if entry_path.extension().is_some_and(|ext| ext == "lox") {
+                    test_paths.push(entry_path.to_string_lossy().into_owned());
+                }
+            }
+            test_paths.sort();
+        }
+        Err(_) => test_paths.push(path.to_string()),
+    }
+
+    for test_path in test_paths {
+        let source = match fs::read_to_string(&test_path) {
+            Ok(source) => source,
+            Err(msg) => {
+                let error = RuntimeError {
+                    message: format!("Failed to read test: {}", msg),
+                    line: 0,
+                };
+                report.record(test_path, Err(error));
+                continue;
+            }
+        };
+
+        // Test output is compared against golden files
+        let mut lox = Lox::new().with_number_format(NumberFormat::Canonical);
+        if let Err(error) = run_test_case(&mut lox, &source) {
+            report.record(test_path, Err(error));
+            continue;
+        }
+
+        let tests: Vec<String> = lox
+            .global_functions()
+            .into_iter()
+            .filter(|name| name.starts_with("test_"))
+            .collect();
+        if tests.is_empty() {
+            report.record(test_path, Ok(()));
+            continue;
+        }
+        for test in tests {
+            let result = run_test_case(&mut lox, &format!("{}();", test));
+            report.record(format!("{}:{}", test_path, test), result);
+        }
+    }
+
+    return report;
+}
+
+// Compile errors are printed here, runtime errors by the vm as they happen.
+fn run_test_case(lox: &mut Lox, source: &str) -> Result<(), RuntimeError> {
+    match lox.run(source) {
+        Ok(()) | Err(LoxError::Interpret(InterpretResult::InterpretExit(0))) => return Ok(()),
+        Err(LoxError::Interpret(result)) => match lox.last_error() {
+            Some(error) => return Err(error.clone()),
+            None => {
+                return Err(RuntimeError {
+                    message: format!("Stopped with a {}.", result),
+                    line: 0,
+                })
+            }
+        },
+        Err(LoxError::Compile(errors)) => {
+            report_compile_errors(&errors);
+            return Err(RuntimeError {
+                message: "Failed to compile.".to_string(),
+                line: errors.first().map_or(0, |error| error.line),
+            });
+        }
+    }
+}
+
+fn test_file(input_path: &str) {
+    let report = run_tests(input_path);
+
+    for (name, error) in &report.errors {
+        println!("[ERROR]: {} (line {}: {})", name, error.line, error.message);
+    }
+    println!("[DONE]: {} passed, {} failed", report.passed, report.failed);
+
+    if report.failed > 0 {
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
+
+const DEFAULT_BENCH_ITERATIONS: u32 = 1000;
+
+#[derive(Debug)]
+struct BenchmarkResult {
+    iterations: u32,
+    min: Duration,
+    max: Duration,
+    mean: Duration,
+    stddev: Duration,
+}
+
+// Compiles once and times `iterations` runs of the same chunk on one vm,
+// globals carry over between runs.
+fn benchmark_source(source: &str, iterations: u32) -> Result<BenchmarkResult, String> {
+    if iterations == 0 {
+        return Err(common::runtime_error(
+            "Benchmark needs at least one iteration.".to_string(),
+        ));
+    }
+
+    let mut compiler = Compiler::new(source.to_string());
+    let chunk = match compiler.to_chunk(Chunk::new()) {
+        Ok(chunk) => chunk,
+        Err(errors) => {
+            report_compile_errors(&errors);
+            return Err(common::compile_error(
+                "Failed to compile benchmark.".to_string(),
+            ));
+        }
+    };
+
+    let mut vm = Vm::new();
+    let mut times: Vec<f64> = vec![];
+    for _ in 0..iterations {
+        let start = Instant::now();
+        if let Err(error) = vm.interpret_chunk(chunk.clone()) {
+            return Err(common::runtime_error(format!(
+                "Benchmark failed with a {}.",
+                error
+            )));
+        }
+        times.push(start.elapsed().as_secs_f64());
+    }
+
+    let count = times.len() as f64;
+    let mean = times.iter().sum::<f64>() / count;
+    let variance = times.iter().map(|time| (time - mean).powi(2)).sum::<f64>() / count;
+    return Ok(BenchmarkResult {
+        iterations,
+        min: Duration::from_secs_f64(times.iter().copied().fold(f64::INFINITY, f64::min)),
+        max: Duration::from_secs_f64(times.iter().copied().fold(0.0, f64::max)),
+        mean: Duration::from_secs_f64(mean),
+        stddev: Duration::from_secs_f64(variance.sqrt()),
+    });
+}
+
+// One csv header and row, times in microseconds.
+fn bench_file(input_path: &str, iterations: u32) -> Result<(), String> {
+    let source = match fs::read_to_string(input_path) {
+        Ok(source) => source,
+        Err(msg) => {
+            return Err(common::runtime_error(format!(
+                "Failed to read file at {}:\n\r{}",
+                input_path, msg
+            )))
+        }
+    };
+
+    let result = benchmark_source(&source, iterations)?;
+    println!("file,iterations,min_us,max_us,mean_us,stddev_us");
+    println!(
+        "{},{},{:.3},{:.3},{:.3},{:.3}",
+        input_path,
+        result.iterations,
+        result.min.as_secs_f64() * 1e6,
+        result.max.as_secs_f64() * 1e6,
+        result.mean.as_secs_f64() * 1e6,
+        result.stddev.as_secs_f64() * 1e6
+    );
+    return Ok(());
+}
+
+// Prints every token with its position, stops at the first scan error.
+fn dump_tokens(input_path: &str) {
+    let source = match fs::read_to_string(input_path) {
+        Ok(source) => source,
+        Err(msg) => {
+            println!(
+                "{}",
+                common::compile_error(format!("Failed to read file at {}:\n\r{}", input_path, msg))
+            );
+            std::process::exit(74);
+        }
+    };
+
+    for token in Scanner::new(source).try_tokens() {
+        match token {
+            Ok(token) => match token.get_type() {
+                TokenType::Identifier | TokenType::String | TokenType::Number => println!(
+                    "{:>4}:{:<4} {} {}",
+                    token.get_line(),
+                    token.get_column(),
+                    token.get_type(),
+                    token.get_lexeme()
+                ),
+                ttype => println!(
+                    "{:>4}:{:<4} {}",
+                    token.get_line(),
+                    token.get_column(),
+                    ttype
+                ),
+            },
+            Err(error) => {
+                println!("[Line {}] Error: {}", error.line, error.message);
+                std::process::exit(65);
+            }
+        }
+    }
+    std::process::exit(0);
+}
+
+// Compiles without writing a bin or running anything, exits with 65 when
+// there are compile errors.
+fn check_file(input_path: &str) {
+    match fs::read_to_string(input_path) {
+        Err(msg) => {
+            println!(
+                "{}",
+                common::compile_error(format!("Failed to read file at {}:\n\r{}", input_path, msg))
+            );
+            std::process::exit(74);
+        }
+        Ok(source) => {
+            let mut compiler = Compiler::new(source);
+            if let Err(errors) = compiler.to_chunk(Chunk::new()) {
+                report_compile_errors(&errors);
+                std::process::exit(65);
+            }
+            std::process::exit(0);
+        }
+    }
+}
+
 fn main() {
     macro_rules! handle_run {
         ($func: expr) => {
@@ -152,13 +465,33 @@ fn main() {
         1 => handle_run!(repl()),
         3 => match args[1].as_str() {
             "run" => handle_run!(run_file(args[2].as_str())),
-            "compile" => handle_run!(compile_file(args[2].as_str())),
+            "compile" => handle_run!(compile_file(args[2].as_str(), false)),
             "execute" => handle_run!(run_bin(args[2].as_str())),
+            "test" => test_file(args[2].as_str()),
+            "check" => check_file(args[2].as_str()),
+            "tokens" => dump_tokens(args[2].as_str()),
+            "bench" => handle_run!(bench_file(args[2].as_str(), DEFAULT_BENCH_ITERATIONS)),
             _ => {
                 println!("[USAGE]: runtime [action] [source]");
                 std::process::exit(64);
             }
         },
+        4 => match (args[1].as_str(), args[3].as_str()) {
+            ("compile", "--strip-debug") => handle_run!(compile_file(args[2].as_str(), true)),
+            ("bench", iterations) => match iterations.parse::<u32>() {
+                Ok(iterations) if iterations > 0 => {
+                    handle_run!(bench_file(args[2].as_str(), iterations))
+                }
+                _ => {
+                    println!("[USAGE]: runtime bench [source] [iterations]");
+                    std::process::exit(64);
+                }
+            },
+            _ => {
+                println!("[USAGE]: runtime compile [source] --strip-debug");
+                std::process::exit(64);
+            }
+        },
         _ => {
             println!("[USAGE]: runtime [action] [source]");
             std::process::exit(64);
@@ -0,0 +1,128 @@
+use std::rc::Rc;
+
+use crate::chunk::Chunk;
+use crate::value::{Value, ValueFormat};
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum ObjType {
+    ObjString,
+    ObjList,
+    ObjFunction,
+    ObjNative,
+}
+
+#[derive(Debug)]
+pub enum Obj {
+    ObjString(String),
+    ObjList(Vec<Value>),
+    ObjFunction(ObjFunction),
+    ObjNative(ObjNative),
+}
+
+#[derive(Debug)]
+pub struct ObjFunction {
+    pub arity: u8,
+    // Parameters from `min_arity` on have default values
+    pub min_arity: u8,
+    // Offset to start at for each number of optional arguments passed, the
+    // last one is the start of the body.
+    pub entry_points: Vec<usize>,
+    // Variables captured from enclosing functions, always 0 until closures
+    // are supported.
+    pub upvalue_count: u8,
+    pub chunk: Rc<Chunk>,
+    // `None` for lambdas
+    pub name: Option<String>,
+}
+
+pub type NativeFn = fn(&[Value]) -> Result<Value, NativeError>;
+
+#[derive(Debug)]
+pub enum NativeError {
+    // Becomes a runtime error in the calling script.
+    Error(String),
+    // Stops the script, the embedder decides what the code means.
+    Exit(i32),
+}
+
+#[derive(Debug)]
+pub struct ObjNative {
+    pub name: String,
+    pub arity: u8,
+    pub function: NativeFn,
+}
+
+impl ObjFunction {
+    // Name used in diagnostics, lambdas show up as `<fn>`.
+    pub fn display_name(&self) -> &str {
+        return self.name.as_deref().unwrap_or("<fn>");
+    }
+}
+
+impl Obj {
+    pub fn get_type(&self) -> ObjType {
+        match self {
+            Obj::ObjString(_) => return ObjType::ObjString,
+            Obj::ObjList(_) => return ObjType::ObjList,
+            Obj::ObjFunction(_) => return ObjType::ObjFunction,
+            Obj::ObjNative(_) => return ObjType::ObjNative,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Obj::ObjString(_) => return "string",
+            Obj::ObjList(_) => return "list",
+            Obj::ObjFunction(_) => return "function",
+            Obj::ObjNative(_) => return "native function",
+        }
+    }
+
+    pub fn approximate_size_bytes(&self) -> usize {
+        // Pointer, length and capacity
+        let header = std::mem::size_of::<usize>() * 3;
+        match self {
+            Obj::ObjString(chars) => return chars.len() + header,
+            Obj::ObjList(elements) => {
+                let elements: usize = elements
+                    .iter()
+                    .map(|element| element.approximate_size_bytes())
+                    .sum();
+                return elements + header;
+            }
+            Obj::ObjFunction(function) => {
+                let constants: usize = function
+                    .chunk
+                    .constants
+                    .iter()
+                    .map(|constant| constant.approximate_size_bytes())
+                    .sum();
+                return std::mem::size_of::<ObjFunction>()
+                    + function.chunk.code_len()
+                    + function.entry_points.len() * std::mem::size_of::<usize>()
+                    + constants;
+            }
+            Obj::ObjNative(native) => {
+                return std::mem::size_of::<ObjNative>() + native.name.len();
+            }
+        }
+    }
+
+    pub fn display_with_format(&self, format: &ValueFormat) -> String {
+        match self {
+            Obj::ObjString(chars) => return chars.clone(),
+            Obj::ObjList(elements) => {
+                let elements: Vec<String> = elements
+                    .iter()
+                    .map(|element| element.display_with_format(format))
+                    .collect();
+                return format!("[{}]", elements.join(", "));
+            }
+            Obj::ObjFunction(function) => match &function.name {
+                Some(name) => return format!("<fn {}>", name),
+                None => return "<fn>".to_string(),
+            },
+            Obj::ObjNative(native) => return format!("<native fn {}>", native.name),
+        }
+    }
+}
@@ -1,5 +1,3 @@
-use std::collections::HashMap;
-
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum TokenType {
     // Single-character tokens.
@@ -14,6 +12,7 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    Percent,
 
     // One or two character tokens.
     Bang,
@@ -22,8 +21,16 @@ pub enum TokenType {
     EqualEqual,
     Greater,
     GreaterEqual,
+    GreaterGreater,
+    GreaterGreaterGreater,
     Less,
     LessEqual,
+    LessLess,
+    DotDot,
+    DotDotEqual,
+    QuestionQuestion,
+    MinusMinus,
+    PlusPlus,
 
     // Literals.
     Identifier,
@@ -38,6 +45,8 @@ pub enum TokenType {
     For,
     Fun,
     If,
+    In,
+    Is,
     Nil,
     Or,
     Print,
@@ -45,33 +54,108 @@ pub enum TokenType {
     Super,
     This,
     True,
+    Typeof,
     Var,
     While,
+    Write,
 
     // Others
     Error,
     EOF,
 }
 
-fn get_keywords() -> HashMap<&'static str, TokenType> {
-    return HashMap::from([
-        ("and", TokenType::And),
-        ("class", TokenType::Class),
-        ("else", TokenType::Else),
-        ("false", TokenType::False),
-        ("for", TokenType::For),
-        ("fun", TokenType::Fun),
-        ("if", TokenType::If),
-        ("nil", TokenType::Nil),
-        ("or", TokenType::Or),
-        ("print", TokenType::Print),
-        ("return", TokenType::Return),
-        ("super", TokenType::Super),
-        ("this", TokenType::This),
-        ("true", TokenType::True),
-        ("var", TokenType::Var),
-        ("while", TokenType::While),
-    ]);
+// Names for diagnostics, punctuation and keywords are quoted as written.
+impl std::fmt::Display for TokenType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            TokenType::LeftParen => "'('",
+            TokenType::RightParen => "')'",
+            TokenType::LeftBrace => "'{'",
+            TokenType::RightBrace => "'}'",
+            TokenType::Comma => "','",
+            TokenType::Dot => "'.'",
+            TokenType::Minus => "'-'",
+            TokenType::Plus => "'+'",
+            TokenType::Semicolon => "';'",
+            TokenType::Slash => "'/'",
+            TokenType::Star => "'*'",
+            TokenType::Percent => "'%'",
+            TokenType::Bang => "'!'",
+            TokenType::BangEqual => "'!='",
+            TokenType::Equal => "'='",
+            TokenType::EqualEqual => "'=='",
+            TokenType::Greater => "'>'",
+            TokenType::GreaterEqual => "'>='",
+            TokenType::GreaterGreater => "'>>'",
+            TokenType::GreaterGreaterGreater => "'>>>'",
+            TokenType::Less => "'<'",
+            TokenType::LessEqual => "'<='",
+            TokenType::LessLess => "'<<'",
+            TokenType::DotDot => "'..'",
+            TokenType::DotDotEqual => "'..='",
+            TokenType::QuestionQuestion => "'??'",
+            TokenType::MinusMinus => "'--'",
+            TokenType::PlusPlus => "'++'",
+            TokenType::Identifier => "identifier",
+            TokenType::String => "string",
+            TokenType::Number => "number",
+            TokenType::And => "'and'",
+            TokenType::Class => "'class'",
+            TokenType::Else => "'else'",
+            TokenType::False => "'false'",
+            TokenType::For => "'for'",
+            TokenType::Fun => "'fun'",
+            TokenType::If => "'if'",
+            TokenType::In => "'in'",
+            TokenType::Is => "'is'",
+            TokenType::Nil => "'nil'",
+            TokenType::Or => "'or'",
+            TokenType::Print => "'print'",
+            TokenType::Return => "'return'",
+            TokenType::Super => "'super'",
+            TokenType::This => "'this'",
+            TokenType::True => "'true'",
+            TokenType::Typeof => "'typeof'",
+            TokenType::Var => "'var'",
+            TokenType::While => "'while'",
+            TokenType::Write => "'write'",
+            TokenType::Error => "error",
+            TokenType::EOF => "end of file",
+        };
+        return write!(f, "{}", name);
+    }
+}
+
+// Sorted by keyword so lookups can binary search, built at compile time
+// instead of once per scanner.
+static KEYWORDS: [(&str, TokenType); 20] = [
+    ("and", TokenType::And),
+    ("class", TokenType::Class),
+    ("else", TokenType::Else),
+    ("false", TokenType::False),
+    ("for", TokenType::For),
+    ("fun", TokenType::Fun),
+    ("if", TokenType::If),
+    ("in", TokenType::In),
+    ("is", TokenType::Is),
+    ("nil", TokenType::Nil),
+    ("or", TokenType::Or),
+    ("print", TokenType::Print),
+    ("return", TokenType::Return),
+    ("super", TokenType::Super),
+    ("this", TokenType::This),
+    ("true", TokenType::True),
+    ("typeof", TokenType::Typeof),
+    ("var", TokenType::Var),
+    ("while", TokenType::While),
+    ("write", TokenType::Write),
+];
+
+fn keyword_type(lexeme: &str) -> Option<TokenType> {
+    return KEYWORDS
+        .binary_search_by_key(&lexeme, |(keyword, _)| keyword)
+        .ok()
+        .map(|index| KEYWORDS[index].1);
 }
 
 #[derive(Clone, Debug)]
@@ -79,6 +163,7 @@ pub struct Token {
     ttype: TokenType,
     lexeme: String,
     line: i32,
+    column: i32,
 }
 
 impl Token {
@@ -86,6 +171,10 @@ impl Token {
         return self.line;
     }
 
+    pub fn get_column(&self) -> i32 {
+        return self.column;
+    }
+
     pub fn get_type(&self) -> TokenType {
         return self.ttype;
     }
@@ -95,13 +184,52 @@ impl Token {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct ScanError {
+    pub line: i32,
+    pub column: i32,
+    pub message: String,
+}
+
+// Sources longer than this are rejected unless the scanner is given another
+// limit with `Scanner::with_max_source_len`.
+pub const DEFAULT_MAX_SOURCE_LEN: usize = 16 * 1024 * 1024;
+
+// Entry point for code outside the compiler that wants Lox tokens. Scans
+// the whole source up to and including EOF; when any token is an error only
+// the error tokens are returned.
+pub fn source_to_tokens(source: &str) -> Result<Vec<Token>, Vec<Token>> {
+    let mut scanner = Scanner::new(source.to_string());
+    let mut tokens = vec![];
+    let mut errors = vec![];
+    loop {
+        let token = scanner.scan_token();
+        let ttype = token.get_type();
+        match ttype {
+            TokenType::Error => errors.push(token),
+            _ => tokens.push(token),
+        }
+        if ttype == TokenType::EOF {
+            break;
+        }
+    }
+
+    if errors.is_empty() {
+        return Ok(tokens);
+    }
+    return Err(errors);
+}
+
 #[derive(Debug)]
 pub struct Scanner {
     start: usize,
     current: usize,
     line: i32,
+    // Offset of the first character on the current line.
+    line_start: usize,
     source: Vec<u8>,
-    keywords: HashMap<&'static str, TokenType>,
+    max_source_len: usize,
+    stashed: Option<Token>,
 }
 
 impl Scanner {
@@ -111,11 +239,97 @@ impl Scanner {
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
             source,
-            keywords: get_keywords(),
+            max_source_len: DEFAULT_MAX_SOURCE_LEN,
+            stashed: None,
         };
     }
 
+    // Starts over on `source`, reusing the buffer of the previous one.
+    pub fn reset(&mut self, source: String) {
+        self.source.clear();
+        self.source.extend_from_slice(source.as_bytes());
+        self.start = 0;
+        self.current = 0;
+        self.line = 1;
+        self.line_start = 0;
+        self.stashed = None;
+    }
+
+    // Source not scanned yet. The scanner walks bytes, so this starts at the
+    // next character boundary.
+    pub fn remaining_source(&self) -> &str {
+        let mut start = self.current.min(self.source.len());
+        while self
+            .source
+            .get(start)
+            .is_some_and(|byte| byte & 0xc0 == 0x80)
+        {
+            start += 1;
+        }
+        return std::str::from_utf8(&self.source[start..]).unwrap_or("");
+    }
+
+    // `remaining_source` up to, not including, the next newline.
+    pub fn current_line_remaining(&self) -> &str {
+        let remaining = self.remaining_source();
+        return remaining.split(['\r', '\n']).next().unwrap_or("");
+    }
+
+    pub fn source_len(&self) -> usize {
+        return self.source.len();
+    }
+
+    // Fraction of the source scanned so far, empty source counts as done.
+    pub fn progress(&self) -> f32 {
+        if self.source.is_empty() {
+            return 1.0;
+        }
+        return (self.current as f32 / self.source.len() as f32).clamp(0.0, 1.0);
+    }
+
+    pub fn with_max_source_len(mut self, max_source_len: usize) -> Self {
+        self.max_source_len = max_source_len;
+        return self;
+    }
+
+    // Every token up to and including EOF, with error tokens turned into
+    // `ScanError`s.
+    pub fn try_tokens(mut self) -> impl Iterator<Item = Result<Token, ScanError>> {
+        let mut done = false;
+        return std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let token = self.scan_token();
+            match token.get_type() {
+                TokenType::Error => {
+                    return Some(Err(ScanError {
+                        line: token.get_line(),
+                        column: token.get_column(),
+                        message: token.get_lexeme(),
+                    }))
+                }
+                TokenType::EOF => done = true,
+                _ => (),
+            }
+            return Some(Ok(token));
+        });
+    }
+
+    // Scans the next token without consuming it, the following call to
+    // `scan_token` returns the same token.
+    pub fn peek_token(&mut self) -> Token {
+        if let Some(token) = &self.stashed {
+            return token.clone();
+        }
+
+        let token = self.scan_token();
+        self.stashed = Some(token.clone());
+        return token;
+    }
+
     pub fn scan_token(&mut self) -> Token {
         macro_rules! token {
             ($ttype:expr) => {
@@ -130,6 +344,21 @@ impl Scanner {
             };
         }
 
+        if let Some(token) = self.stashed.take() {
+            return token;
+        }
+
+        // Reported once, the source is dropped so scanning ends with EOF
+        if self.source.len() > self.max_source_len {
+            let message = format!(
+                "Source is {} bytes, more than the limit of {}.",
+                self.source.len(),
+                self.max_source_len
+            );
+            self.source.clear();
+            return self.error_token(message);
+        }
+
         self.skip_whitespace();
         self.start = self.current;
 
@@ -153,16 +382,40 @@ impl Scanner {
                 '}' => token!(TokenType::RightBrace),
                 ';' => token!(TokenType::Semicolon),
                 ',' => token!(TokenType::Comma),
-                '.' => token!(TokenType::Dot),
-                '-' => token!(TokenType::Minus),
-                '+' => token!(TokenType::Plus),
+                '.' => {
+                    if self.match_char('.') {
+                        token!('=', TokenType::DotDotEqual, TokenType::DotDot);
+                    }
+                    token!(TokenType::Dot)
+                }
+                '-' => token!('-', TokenType::MinusMinus, TokenType::Minus),
+                '+' => token!('+', TokenType::PlusPlus, TokenType::Plus),
                 '/' => token!(TokenType::Slash),
                 '*' => token!(TokenType::Star),
+                '%' => token!(TokenType::Percent),
                 '!' => token!('=', TokenType::BangEqual, TokenType::Bang),
                 '=' => token!('=', TokenType::EqualEqual, TokenType::Equal),
-                '<' => token!('=', TokenType::LessEqual, TokenType::Less),
-                '>' => token!('=', TokenType::GreaterEqual, TokenType::Greater),
+                '<' => {
+                    if self.match_char('<') {
+                        token!(TokenType::LessLess);
+                    }
+                    token!('=', TokenType::LessEqual, TokenType::Less)
+                }
+                '>' => {
+                    if self.match_char('>') {
+                        token!(
+                            '>',
+                            TokenType::GreaterGreaterGreater,
+                            TokenType::GreaterGreater
+                        );
+                    }
+                    token!('=', TokenType::GreaterEqual, TokenType::Greater)
+                }
+                '?' if self.match_char('?') => token!(TokenType::QuestionQuestion),
                 '"' => return self.string(),
+                '\\' => {
+                    return self.error_token("Expect newline after '\\'.".to_string());
+                }
                 _ => (),
             };
         }
@@ -172,10 +425,9 @@ impl Scanner {
 
     fn string(&mut self) -> Token {
         while self.peek() != Some('"') && !self.is_at_end() {
-            if self.peek() == Some('\n') {
-                self.line += 1;
+            if !self.match_line_break() {
+                self.advance();
             }
-            self.advance();
         }
 
         if self.is_at_end() {
@@ -188,16 +440,11 @@ impl Scanner {
     }
 
     fn number(&mut self) -> Token {
-        loop {
-            match self.peek() {
-                Some(current_char) => {
-                    if !self.is_digit(current_char) {
-                        break;
-                    }
-                    self.advance();
-                }
-                _ => break,
-            };
+        while let Some(current_char) = self.peek() {
+            if !self.is_digit(current_char) {
+                break;
+            }
+            self.advance();
         }
 
         // Decimals
@@ -206,16 +453,20 @@ impl Scanner {
                 // consume '.'
                 self.advance();
 
-                loop {
-                    match self.peek() {
-                        Some(current_char) => {
-                            if !self.is_digit(current_char) {
-                                break;
-                            }
-                            self.advance();
-                        }
-                        _ => break,
-                    };
+                while let Some(current_char) = self.peek() {
+                    if !self.is_digit(current_char) {
+                        break;
+                    }
+                    self.advance();
+                }
+
+                // `1.2.3`, the rest of it is skipped so it's one error. A dot
+                // followed by anything else is left for `..` and the like.
+                if self.peek() == Some('.') && self.peek_next().is_some_and(|c| self.is_digit(c)) {
+                    while self.peek().is_some_and(|c| c == '.' || self.is_digit(c)) {
+                        self.advance();
+                    }
+                    return self.error_token("Malformed number literal.".to_string());
                 }
             };
         };
@@ -224,16 +475,11 @@ impl Scanner {
     }
 
     fn identifier(&mut self) -> Token {
-        loop {
-            match self.peek() {
-                Some(current_char) => {
-                    if !self.is_alpha(current_char) && !self.is_digit(current_char) {
-                        break;
-                    }
-                    self.advance();
-                }
-                _ => break,
-            };
+        while let Some(current_char) = self.peek() {
+            if !self.is_alpha(current_char) && !self.is_digit(current_char) {
+                break;
+            }
+            self.advance();
         }
 
         match self.source.get(self.start..self.current) {
@@ -241,14 +487,15 @@ impl Scanner {
                 let lexeme: String = String::from_utf8_lossy(bytes).into_owned();
                 let mut ttype = TokenType::Identifier;
 
-                if let Some(token_type) = self.keywords.get(&lexeme[..]) {
-                    ttype = token_type.clone();
+                if let Some(token_type) = keyword_type(&lexeme) {
+                    ttype = token_type;
                 }
 
                 return Token {
                     ttype,
                     lexeme,
                     line: self.line,
+                    column: self.column(),
                 };
             }
             None => {
@@ -263,18 +510,18 @@ impl Scanner {
     }
 
     fn is_digit(&self, c: char) -> bool {
-        return c >= '0' && c <= '9';
+        return c.is_ascii_digit();
     }
 
     fn is_alpha(&self, c: char) -> bool {
-        return c >= 'a' && c <= 'z' || c >= 'A' && c <= 'Z' || c == '_';
+        return c.is_ascii_alphabetic() || c == '_';
     }
 
     fn is_at_end(&mut self) -> bool {
-        if let Some(current) = self.peek() {
-            return current == '\0';
+        match self.peek() {
+            Some(current) => return current == '\0',
+            None => return true,
         }
-        return true;
     }
 
     fn peek(&mut self) -> Option<char> {
@@ -287,7 +534,7 @@ impl Scanner {
 
     fn peek_next(&mut self) -> Option<char> {
         if self.is_at_end() {
-            return Some('\0');
+            return None;
         }
 
         if let Some(current_char) = self.source.get(self.current + 1) {
@@ -297,9 +544,12 @@ impl Scanner {
         return None;
     }
 
+    // Never moves past the end of the source.
     fn advance(&mut self) -> Option<char> {
         let c = self.peek();
-        self.current += 1;
+        if c.is_some() {
+            self.current += 1;
+        }
         return c;
     }
 
@@ -322,28 +572,46 @@ impl Scanner {
         loop {
             let c = self.peek();
             match c {
-                Some(' ') | Some('\r') | Some('\t') => {
+                Some(' ') | Some('\t') => {
                     self.advance();
                 }
-                Some('\n') => {
-                    self.line += 1;
+                Some('\r') | Some('\n') => {
+                    self.match_line_break();
+                }
+                Some('\\') => {
+                    // Line continuation, joins the next line onto this one
+                    if !matches!(self.peek_next(), Some('\r') | Some('\n')) {
+                        return;
+                    }
                     self.advance();
+                    self.match_line_break();
                 }
                 Some('/') => {
                     match self.peek_next() {
                         Some('/') => {
-                            while self.peek() != Some('\n') && !self.is_at_end() {
+                            while !matches!(self.peek(), Some('\r') | Some('\n'))
+                                && !self.is_at_end()
+                            {
                                 self.advance();
                             }
                         }
                         Some('*') => {
-                            while self.peek() != Some('*')
-                                && self.peek_next() != Some('/')
-                                && !self.is_at_end()
-                            {
-                                self.advance();
+                            // Skips the opening `/*`, an unclosed comment
+                            // runs to the end of the source
+                            self.advance();
+                            self.advance();
+                            loop {
+                                let closing =
+                                    self.peek() == Some('*') && self.peek_next() == Some('/');
+                                if closing || self.is_at_end() {
+                                    break;
+                                }
+                                if !self.match_line_break() {
+                                    self.advance();
+                                }
                             }
                             self.advance();
+                            self.advance();
                         }
                         _ => return,
                     };
@@ -361,6 +629,7 @@ impl Scanner {
                     ttype,
                     lexeme,
                     line: self.line,
+                    column: self.column(),
                 };
             }
             None => {
@@ -374,11 +643,237 @@ impl Scanner {
         };
     }
 
+    fn column(&self) -> i32 {
+        return (self.start.saturating_sub(self.line_start) + 1) as i32;
+    }
+
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
+    // Consumes the line break at `current`, if there is one. `\r\n` and a
+    // lone `\r` count as a single line break like `\n`.
+    fn match_line_break(&mut self) -> bool {
+        match self.peek() {
+            Some('\r') => {
+                self.advance();
+                if self.peek() == Some('\n') {
+                    self.advance();
+                }
+            }
+            Some('\n') => {
+                self.advance();
+            }
+            _ => return false,
+        }
+        self.new_line();
+        return true;
+    }
+
     fn error_token(&self, message: String) -> Token {
         return Token {
             ttype: TokenType::Error,
             lexeme: message,
             line: self.line,
+            column: self.column(),
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every token up to and including EOF.
+    fn scan(source: &str) -> Vec<Token> {
+        let mut scanner = Scanner::new(source.to_string());
+        let mut tokens = vec![];
+        loop {
+            let token = scanner.scan_token();
+            let at_end = token.get_type() == TokenType::EOF;
+            tokens.push(token);
+            if at_end {
+                return tokens;
+            }
+        }
+    }
+
+    fn types(source: &str) -> Vec<TokenType> {
+        return scan(source).iter().map(|token| token.get_type()).collect();
+    }
+
+    #[test]
+    fn backslash_newline_joins_lines() {
+        use TokenType::*;
+        let tokens = scan("1 + \\\n2");
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|token| token.get_type())
+                .collect::<Vec<_>>(),
+            [Number, Plus, Number, EOF]
+        );
+        assert_eq!(tokens[2].get_line(), 2);
+        assert_eq!(types("1 + \\\r\n2"), types("1 + 2"));
+    }
+
+    #[test]
+    fn backslash_before_anything_else_is_an_error() {
+        let tokens = scan("1 \\ 2");
+        assert_eq!(tokens[1].get_type(), TokenType::Error);
+        assert_eq!(tokens[1].get_lexeme(), "Expect newline after '\\'.");
+        assert_eq!(types("\\"), [TokenType::Error, TokenType::EOF]);
+    }
+
+    #[test]
+    fn peek_token_does_not_consume() {
+        let mut scanner = Scanner::new("a (".to_string());
+        assert_eq!(scanner.peek_token().get_type(), TokenType::Identifier);
+        assert_eq!(scanner.peek_token().get_lexeme(), "a");
+        assert_eq!(scanner.scan_token().get_lexeme(), "a");
+        assert_eq!(scanner.peek_token().get_type(), TokenType::LeftParen);
+        assert_eq!(scanner.scan_token().get_type(), TokenType::LeftParen);
+        assert_eq!(scanner.scan_token().get_type(), TokenType::EOF);
+    }
+
+    #[test]
+    fn source_to_tokens_returns_all_tokens_or_only_errors() {
+        let tokens = source_to_tokens("var a = 1;").unwrap();
+        assert_eq!(tokens.len(), 6);
+        assert_eq!(tokens[5].get_type(), TokenType::EOF);
+
+        let errors = source_to_tokens("var a = @ # 1;").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors
+            .iter()
+            .all(|token| token.get_type() == TokenType::Error));
+    }
+
+    #[test]
+    fn try_tokens_turns_bad_characters_into_errors() {
+        let results: Vec<_> = Scanner::new("a @ b".to_string()).try_tokens().collect();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().unwrap().get_lexeme(), "a");
+        let error = results[1].as_ref().unwrap_err();
+        assert_eq!((error.line, error.column), (1, 3));
+        assert_eq!(error.message, "Unexpected character.");
+        assert_eq!(results[2].as_ref().unwrap().get_lexeme(), "b");
+        assert_eq!(results[3].as_ref().unwrap().get_type(), TokenType::EOF);
+    }
+
+    #[test]
+    fn source_at_the_size_limit_is_scanned() {
+        let scanner = Scanner::new("1 + 2".to_string()).with_max_source_len(5);
+        let tokens: Vec<_> = scanner.try_tokens().collect();
+        assert_eq!(tokens.len(), 4);
+        assert!(tokens.iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn source_over_the_size_limit_is_one_error() {
+        let mut scanner = Scanner::new("1 + 23".to_string()).with_max_source_len(5);
+        let token = scanner.scan_token();
+        assert_eq!(token.get_type(), TokenType::Error);
+        assert_eq!(
+            token.get_lexeme(),
+            "Source is 6 bytes, more than the limit of 5."
+        );
+        assert_eq!(scanner.scan_token().get_type(), TokenType::EOF);
+        assert_eq!(scanner.scan_token().get_type(), TokenType::EOF);
+    }
+
+    #[test]
+    fn number_at_the_end_of_the_source() {
+        let tokens = scan("1.5");
+        assert_eq!(tokens[0].get_lexeme(), "1.5");
+        assert_eq!(tokens[1].get_type(), TokenType::EOF);
+        assert_eq!(
+            types("1."),
+            [TokenType::Number, TokenType::Dot, TokenType::EOF]
+        );
+    }
+
+    #[test]
+    fn slash_and_comments_at_the_end_of_the_source() {
+        assert_eq!(types("/"), [TokenType::Slash, TokenType::EOF]);
+        assert_eq!(
+            types("1 /"),
+            [TokenType::Number, TokenType::Slash, TokenType::EOF]
+        );
+        assert_eq!(types("1 /* open"), [TokenType::Number, TokenType::EOF]);
+        assert_eq!(types("1 /*"), [TokenType::Number, TokenType::EOF]);
+        assert_eq!(types("1 //"), [TokenType::Number, TokenType::EOF]);
+    }
+
+    #[test]
+    fn reset_scans_like_a_fresh_scanner() {
+        let mut scanner = Scanner::new("print \"first\";\nvar x;".to_string());
+        while scanner.scan_token().get_type() != TokenType::EOF {}
+
+        let source = "var long_name = 1.5;\n// comment\nprint long_name;";
+        scanner.reset(source.to_string());
+        for expected in scan(source) {
+            let token = scanner.scan_token();
+            assert_eq!(token.get_type(), expected.get_type());
+            assert_eq!(token.get_lexeme(), expected.get_lexeme());
+            assert_eq!(token.get_line(), expected.get_line());
+            assert_eq!(token.get_column(), expected.get_column());
+        }
+    }
+
+    #[test]
+    fn reset_drops_a_peeked_token() {
+        let mut scanner = Scanner::new("a".to_string());
+        scanner.peek_token();
+        scanner.reset("b".to_string());
+        assert_eq!(scanner.scan_token().get_lexeme(), "b");
+    }
+
+    #[test]
+    fn token_types_display_readable_names() {
+        let cases = [
+            (TokenType::Identifier, "identifier"),
+            (TokenType::Plus, "'+'"),
+            (TokenType::BangEqual, "'!='"),
+            (TokenType::Number, "number"),
+            (TokenType::String, "string"),
+            (TokenType::While, "'while'"),
+            (TokenType::EOF, "end of file"),
+        ];
+        for (ttype, name) in cases {
+            assert_eq!(ttype.to_string(), name);
+        }
+    }
+
+    #[test]
+    fn second_decimal_point_is_a_malformed_number() {
+        let tokens = scan("1.2.3;");
+        assert_eq!(tokens[0].get_type(), TokenType::Error);
+        assert_eq!(tokens[0].get_lexeme(), "Malformed number literal.");
+        assert_eq!(tokens[1].get_type(), TokenType::Semicolon);
+
+        let tokens = scan("1.2;");
+        assert_eq!(tokens[0].get_type(), TokenType::Number);
+        assert_eq!(tokens[0].get_lexeme(), "1.2");
+        assert_eq!(types("1.2..3"), types("1.2 .. 3"));
+    }
+
+    fn lines(source: &str) -> Vec<i32> {
+        return scan(source).iter().map(|token| token.get_line()).collect();
+    }
+
+    #[test]
+    fn crlf_and_lone_cr_are_one_line_break() {
+        assert_eq!(lines("a\r\nb\r\nc"), [1, 2, 3, 3]);
+        assert_eq!(lines("a\rb\rc"), [1, 2, 3, 3]);
+        assert_eq!(lines("a\r\n\r\nb\n\rc"), [1, 3, 5, 5]);
+        assert_eq!(lines("// x\r\na /* \r\n */ b"), [2, 3, 3]);
+    }
+
+    #[test]
+    fn line_breaks_in_strings_are_counted() {
+        assert_eq!(lines("\"a\r\nb\" c"), [2, 2, 2]);
+        assert_eq!(lines("\"a\rb\" c"), [2, 2, 2]);
+    }
+}
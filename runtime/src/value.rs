@@ -1,32 +1,54 @@
+use std::rc::Rc;
+
+use crate::object::{Obj, ObjType};
+
 #[derive(PartialEq, Debug, Clone)]
 pub enum ValueType {
     ValBool,
     ValNil,
     ValNumber,
+    ValObj,
 }
 
-#[derive(Clone, Copy)]
-pub union ValuePayload {
-    boolean: Boolean,
-    number: Number,
+impl ValueType {
+    // Inverse of `value_type as u8`, the operand of OpAssertType.
+    pub fn from_tag(tag: u8) -> Option<ValueType> {
+        match tag {
+            0 => return Some(ValueType::ValBool),
+            1 => return Some(ValueType::ValNil),
+            2 => return Some(ValueType::ValNumber),
+            3 => return Some(ValueType::ValObj),
+            _ => return None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ValueType::ValBool => return "bool",
+            ValueType::ValNil => return "nil",
+            ValueType::ValNumber => return "number",
+            ValueType::ValObj => return "object",
+        }
+    }
 }
 
+// Each variant carries only its own payload, so reading a value as the wrong
+// type can't reinterpret another type's bits.
 #[derive(Clone)]
-pub struct Value {
-    value_type: ValueType,
-    as_union: ValuePayload,
+pub enum Value {
+    ValBool(Boolean),
+    ValNil,
+    ValNumber(Number),
+    ValObj(Rc<Obj>),
 }
 
 impl std::fmt::Debug for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.value_type {
-            ValueType::ValBool => {
-                write!(f, "Value {{ {:?}: {} }}", self.value_type, self.as_bool())
-            }
-            ValueType::ValNil => write!(f, "Value {{ {:?} }}", self.value_type),
-            ValueType::ValNumber => {
-                write!(f, "Value {{ {:?}: {} }}", self.value_type, self.as_number())
-            }
+        match self {
+            Value::ValBool(boolean) => write!(f, "Value {{ ValBool: {} }}", boolean),
+            Value::ValNil => write!(f, "Value {{ ValNil }}"),
+            Value::ValNumber(number) => write!(f, "Value {{ ValNumber: {} }}", number),
+            Value::ValObj(obj) => write!(f, "Value {{ ValObj: {:?} }}", Some(obj)),
         }
     }
 }
@@ -34,67 +56,597 @@ impl std::fmt::Debug for Value {
 pub type Boolean = bool;
 pub type Number = f64;
 
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum NumberFormat {
+    // Rust's shortest round-trip formatting, `3.0` prints as `3`.
+    #[default]
+    Default,
+    // Always this many decimal places.
+    Fixed(usize),
+    // Like `Default` but integral numbers keep a `.0`.
+    AlwaysDecimal,
+    // Stable output for golden tests, see `format_canonical`.
+    Canonical,
+}
+
+pub fn format_number(number: Number, format: NumberFormat) -> String {
+    match format {
+        NumberFormat::Default => return format!("{}", number),
+        NumberFormat::Fixed(precision) => return format!("{:.*}", precision, number),
+        NumberFormat::AlwaysDecimal => {
+            if number.is_finite() && number.fract() == 0.0 {
+                return format!("{:.1}", number);
+            }
+            return format!("{}", number);
+        }
+        NumberFormat::Canonical => return format_canonical(number),
+    }
+}
+
+// Integers print without a decimal point and `-0` as `0`. Other numbers use
+// the shortest round-trip digits, switching to scientific notation outside
+// [1e-7, 1e21) so huge or tiny values don't expand into long digit strings.
+fn format_canonical(number: Number) -> String {
+    if number.is_nan() {
+        return "nan".to_string();
+    }
+    if number.is_infinite() {
+        return match number > 0.0 {
+            true => "inf".to_string(),
+            false => "-inf".to_string(),
+        };
+    }
+    if number == 0.0 {
+        return "0".to_string();
+    }
+
+    let magnitude = number.abs();
+    if !(1e-7..1e21).contains(&magnitude) {
+        return format!("{:e}", number);
+    }
+    return format!("{}", number);
+}
+
+// Formatting of values for display, layered on top of a `NumberFormat`.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct ValueFormat {
+    pub number_format: NumberFormat,
+    // Decimal places for every number, overrides the number format.
+    pub number_precision: Option<usize>,
+    // Numbers at least this large in magnitude use scientific notation.
+    pub use_exponential_above: Option<f64>,
+}
+
+impl ValueFormat {
+    pub fn format_number(&self, number: Number) -> String {
+        let exponential = self
+            .use_exponential_above
+            .is_some_and(|threshold| number.is_finite() && number.abs() >= threshold);
+
+        match (exponential, self.number_precision) {
+            (true, Some(precision)) => return format!("{:.*e}", precision, number),
+            (true, None) => return format!("{:e}", number),
+            (false, Some(precision)) => return format!("{:.*}", precision, number),
+            (false, None) => return format_number(number, self.number_format),
+        }
+    }
+}
+
 impl Value {
+    pub const NIL: Value = Value::ValNil;
+    pub const TRUE: Value = Value::ValBool(true);
+    pub const FALSE: Value = Value::ValBool(false);
+
+    pub fn nil() -> Self {
+        return Self::NIL;
+    }
+
     pub fn from_bool(value: Boolean) -> Self {
-        Self {
-            value_type: ValueType::ValBool,
-            as_union: ValuePayload { boolean: value },
-        }
+        return Value::ValBool(value);
     }
 
     pub fn from_nil() -> Self {
-        Self {
-            value_type: ValueType::ValNil,
-            as_union: ValuePayload { number: 0.0 },
-        }
+        return Value::ValNil;
     }
 
     pub fn from_number(value: Number) -> Self {
-        Self {
-            value_type: ValueType::ValNumber,
-            as_union: ValuePayload { number: value },
-        }
+        return Value::ValNumber(value);
+    }
+
+    pub fn from_obj(obj: Rc<Obj>) -> Self {
+        return Value::ValObj(obj);
+    }
+
+    pub fn from_string(chars: String) -> Self {
+        return Self::from_obj(Rc::new(Obj::ObjString(chars)));
     }
 
+    // Accessors give a neutral value for the wrong type instead of
+    // panicking, callers check the type first.
     pub fn as_bool(&self) -> Boolean {
-        return unsafe { self.as_union.boolean };
+        match self {
+            Value::ValBool(boolean) => return *boolean,
+            _ => return false,
+        }
     }
 
     pub fn as_number(&self) -> Number {
-        return unsafe { self.as_union.number };
+        match self {
+            Value::ValNumber(number) => return *number,
+            _ => return 0.0,
+        }
+    }
+
+    pub fn as_obj(&self) -> Option<&Rc<Obj>> {
+        match self {
+            Value::ValObj(obj) => return Some(obj),
+            _ => return None,
+        }
+    }
+
+    pub fn as_string(&self) -> &str {
+        match self.as_obj().map(|obj| &**obj) {
+            Some(Obj::ObjString(chars)) => return chars,
+            _ => return "",
+        }
+    }
+
+    // Integers are the numbers without a fractional part which an f64 can
+    // represent exactly, anything else gives 0.
+    pub fn as_integer(&self) -> i64 {
+        if !self.is_integer() {
+            return 0;
+        }
+        return self.as_number() as i64;
     }
 
     pub fn is_bool(&self) -> bool {
-        return self.value_type == ValueType::ValBool;
+        return matches!(self, Value::ValBool(_));
     }
 
     pub fn is_nil(&self) -> bool {
-        return self.value_type == ValueType::ValNil;
+        return matches!(self, Value::ValNil);
     }
 
     pub fn is_number(&self) -> bool {
-        return self.value_type == ValueType::ValNumber;
+        return matches!(self, Value::ValNumber(_));
+    }
+
+    pub fn is_integer(&self) -> bool {
+        return self.is_number()
+            && self.as_number().fract() == 0.0
+            && self.as_number().abs() < 2.0_f64.powi(53);
+    }
+
+    pub fn is_obj_type(&self, obj_type: ObjType) -> bool {
+        match self.as_obj() {
+            Some(obj) => return obj.get_type() == obj_type,
+            None => return false,
+        }
+    }
+
+    pub fn is_function(&self) -> bool {
+        return self.is_obj_type(ObjType::ObjFunction);
+    }
+
+    pub fn is_string(&self) -> bool {
+        return self.is_obj_type(ObjType::ObjString);
     }
 
     pub fn get_type(&self) -> &ValueType {
-        return &self.value_type;
+        match self {
+            Value::ValBool(_) => return &ValueType::ValBool,
+            Value::ValNil => return &ValueType::ValNil,
+            Value::ValNumber(_) => return &ValueType::ValNumber,
+            Value::ValObj(_) => return &ValueType::ValObj,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::ValBool(_) => return "bool",
+            Value::ValNil => return "nil",
+            Value::ValNumber(_) => return "number",
+            Value::ValObj(obj) => return obj.type_name(),
+        }
+    }
+
+    // Rough heap footprint for memory accounting, not what the allocator
+    // actually hands out. Shared objects are counted once per reference.
+    pub fn approximate_size_bytes(&self) -> usize {
+        match self {
+            Value::ValBool(_) | Value::ValNil | Value::ValNumber(_) => return 8,
+            Value::ValObj(obj) => return obj.approximate_size_bytes(),
+        }
     }
 
     pub fn print(&self) {
-        match self.value_type {
-            ValueType::ValBool => {
-                if self.as_bool() {
-                    print!("true");
-                } else {
-                    print!("false");
-                }
-            }
-            ValueType::ValNil => {
-                print!("nil")
+        self.print_with(NumberFormat::Default);
+    }
+
+    pub fn print_with(&self, number_format: NumberFormat) {
+        let format = ValueFormat {
+            number_format,
+            ..ValueFormat::default()
+        };
+        print!("{}", self.display_with_format(&format));
+    }
+
+    pub fn to_canonical_string(&self) -> String {
+        let format = ValueFormat {
+            number_format: NumberFormat::Canonical,
+            ..ValueFormat::default()
+        };
+        return self.display_with_format(&format);
+    }
+
+    pub fn display_with_format(&self, format: &ValueFormat) -> String {
+        match self {
+            Value::ValBool(boolean) => return boolean.to_string(),
+            Value::ValNil => return "nil".to_string(),
+            Value::ValNumber(number) => return format.format_number(*number),
+            Value::ValObj(obj) => return obj.display_with_format(format),
+        }
+    }
+
+    // MessagePack encoding, numbers are always written as float64. Functions
+    // have no MessagePack form and fail to serialize.
+    pub fn serialize_msgpack(&self) -> Result<Vec<u8>, String> {
+        let mut bytes = vec![];
+        write_msgpack(self, &mut bytes)?;
+        return Ok(bytes);
+    }
+
+    // Integers of any width decode as numbers too, so values written by other
+    // MessagePack encoders can be read back.
+    pub fn deserialize_msgpack(bytes: &[u8]) -> Result<Value, String> {
+        let mut offset = 0;
+        let value = read_msgpack(bytes, &mut offset, 0)?;
+        if offset != bytes.len() {
+            return Err(format!("Unexpected trailing bytes at offset {}.", offset));
+        }
+        return Ok(value);
+    }
+}
+
+// Arrays nested deeper than this are rejected rather than decoded
+// recursively until the stack runs out.
+const MSGPACK_MAX_DEPTH: usize = 512;
+
+fn write_msgpack_str_header(bytes: &mut Vec<u8>, len: usize) {
+    if len < 32 {
+        bytes.push(0xa0 | len as u8);
+    } else if len <= u8::MAX as usize {
+        bytes.extend([0xd9, len as u8]);
+    } else if len <= u16::MAX as usize {
+        bytes.push(0xda);
+        bytes.extend((len as u16).to_be_bytes());
+    } else {
+        bytes.push(0xdb);
+        bytes.extend((len as u32).to_be_bytes());
+    }
+}
+
+fn write_msgpack_array_header(bytes: &mut Vec<u8>, len: usize) {
+    if len < 16 {
+        bytes.push(0x90 | len as u8);
+    } else if len <= u16::MAX as usize {
+        bytes.push(0xdc);
+        bytes.extend((len as u16).to_be_bytes());
+    } else {
+        bytes.push(0xdd);
+        bytes.extend((len as u32).to_be_bytes());
+    }
+}
+
+fn write_msgpack(value: &Value, bytes: &mut Vec<u8>) -> Result<(), String> {
+    match value {
+        Value::ValNil => bytes.push(0xc0),
+        Value::ValBool(false) => bytes.push(0xc2),
+        Value::ValBool(true) => bytes.push(0xc3),
+        Value::ValNumber(number) => {
+            bytes.push(0xcb);
+            bytes.extend(number.to_be_bytes());
+        }
+        Value::ValObj(obj) => match &**obj {
+            Obj::ObjString(chars) => {
+                write_msgpack_str_header(bytes, chars.len());
+                bytes.extend(chars.as_bytes());
             }
-            ValueType::ValNumber => {
-                print!("{}", self.as_number());
+            Obj::ObjList(elements) => {
+                write_msgpack_array_header(bytes, elements.len());
+                for element in elements {
+                    write_msgpack(element, bytes)?;
+                }
             }
+            _ => return Err(format!("Can't serialize a {}.", obj.type_name())),
+        },
+    }
+    return Ok(());
+}
+
+fn read_msgpack_bytes<'a>(
+    bytes: &'a [u8],
+    offset: &mut usize,
+    len: usize,
+) -> Result<&'a [u8], String> {
+    match bytes.get(*offset..*offset + len) {
+        Some(slice) => {
+            *offset += len;
+            return Ok(slice);
+        }
+        None => return Err(format!("Unexpected end of input at offset {}.", *offset)),
+    }
+}
+
+// Big endian unsigned integer of `len` bytes.
+fn read_msgpack_uint(bytes: &[u8], offset: &mut usize, len: usize) -> Result<u64, String> {
+    let slice = read_msgpack_bytes(bytes, offset, len)?;
+    return Ok(slice.iter().fold(0, |acc, byte| (acc << 8) | *byte as u64));
+}
+
+// Big endian two's complement integer of `len` bytes.
+fn read_msgpack_int(bytes: &[u8], offset: &mut usize, len: usize) -> Result<i64, String> {
+    let unsigned = read_msgpack_uint(bytes, offset, len)?;
+    let shift = 64 - len as u32 * 8;
+    return Ok(((unsigned << shift) as i64) >> shift);
+}
+
+fn read_msgpack_str(bytes: &[u8], offset: &mut usize, len: usize) -> Result<Value, String> {
+    let slice = read_msgpack_bytes(bytes, offset, len)?;
+    match std::str::from_utf8(slice) {
+        Ok(chars) => return Ok(Value::from_string(chars.to_string())),
+        Err(_) => return Err("Invalid UTF-8 in string.".to_string()),
+    }
+}
+
+fn read_msgpack_array(
+    bytes: &[u8],
+    offset: &mut usize,
+    len: usize,
+    depth: usize,
+) -> Result<Value, String> {
+    if depth >= MSGPACK_MAX_DEPTH {
+        return Err("Nesting too deep.".to_string());
+    }
+    let mut elements = vec![];
+    for _ in 0..len {
+        elements.push(read_msgpack(bytes, offset, depth + 1)?);
+    }
+    return Ok(Value::from_obj(Rc::new(Obj::ObjList(elements))));
+}
+
+// `depth` counts the arrays around the value being read.
+fn read_msgpack(bytes: &[u8], offset: &mut usize, depth: usize) -> Result<Value, String> {
+    let marker = read_msgpack_bytes(bytes, offset, 1)?[0];
+    match marker {
+        0x00..=0x7f => return Ok(Value::from_number(marker as Number)),
+        0x90..=0x9f => return read_msgpack_array(bytes, offset, (marker & 0x0f) as usize, depth),
+        0xa0..=0xbf => return read_msgpack_str(bytes, offset, (marker & 0x1f) as usize),
+        0xc0 => return Ok(Value::NIL),
+        0xc2 => return Ok(Value::FALSE),
+        0xc3 => return Ok(Value::TRUE),
+        0xca => {
+            let bits = read_msgpack_uint(bytes, offset, 4)? as u32;
+            return Ok(Value::from_number(f32::from_bits(bits) as Number));
+        }
+        0xcb => {
+            let bits = read_msgpack_uint(bytes, offset, 8)?;
+            return Ok(Value::from_number(f64::from_bits(bits)));
+        }
+        0xcc..=0xcf => {
+            let len = 1 << (marker - 0xcc);
+            return Ok(Value::from_number(
+                read_msgpack_uint(bytes, offset, len)? as Number
+            ));
+        }
+        0xd0..=0xd3 => {
+            let len = 1 << (marker - 0xd0);
+            return Ok(Value::from_number(
+                read_msgpack_int(bytes, offset, len)? as Number
+            ));
+        }
+        0xd9..=0xdb => {
+            let len = read_msgpack_uint(bytes, offset, 1 << (marker - 0xd9))? as usize;
+            return read_msgpack_str(bytes, offset, len);
+        }
+        0xdc | 0xdd => {
+            let len = read_msgpack_uint(bytes, offset, 2 << (marker - 0xdc))? as usize;
+            return read_msgpack_array(bytes, offset, len, depth);
+        }
+        0xe0..=0xff => return Ok(Value::from_number(marker as i8 as Number)),
+        _ => {
+            return Err(format!(
+                "Unsupported MessagePack type 0x{:02x} at offset {}.",
+                marker,
+                *offset - 1
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Values have no `PartialEq`, their type and printed form stand in.
+    fn describe(value: &Value) -> String {
+        let format = ValueFormat::default();
+        return format!(
+            "{} {}",
+            value.type_name(),
+            value.display_with_format(&format)
+        );
+    }
+
+    fn round_trip(value: Value) {
+        let bytes = value.serialize_msgpack().unwrap();
+        let decoded = Value::deserialize_msgpack(&bytes).unwrap();
+        assert_eq!(describe(&decoded), describe(&value));
+    }
+
+    fn list(elements: Vec<Value>) -> Value {
+        return Value::from_obj(Rc::new(Obj::ObjList(elements)));
+    }
+
+    #[test]
+    fn msgpack_round_trip_of_each_type() {
+        round_trip(Value::NIL);
+        round_trip(Value::TRUE);
+        round_trip(Value::FALSE);
+        for number in [0.0, -1.5, 1e300, f64::INFINITY] {
+            round_trip(Value::from_number(number));
+        }
+        for len in [0, 31, 32, 255, 256, 70000] {
+            round_trip(Value::from_string("x".repeat(len)));
+        }
+        let nested = list(vec![
+            Value::from_number(1.0),
+            Value::from_string("a".to_string()),
+        ]);
+        round_trip(list(vec![Value::NIL, nested, list(vec![])]));
+        round_trip(list(vec![Value::TRUE; 16]));
+    }
+
+    #[test]
+    fn msgpack_numbers_are_float64() {
+        let bytes = Value::from_number(1.0).serialize_msgpack().unwrap();
+        assert_eq!(bytes, [&[0xcb][..], &1.0_f64.to_be_bytes()].concat());
+    }
+
+    #[test]
+    fn msgpack_integers_decode_as_numbers() {
+        let cases: [(&[u8], f64); 4] = [
+            (&[0x05], 5.0),
+            (&[0xff], -1.0),
+            (&[0xcd, 0x01, 0x00], 256.0),
+            (&[0xd0, 0x80], -128.0),
+        ];
+        for (bytes, number) in cases {
+            assert_eq!(
+                Value::deserialize_msgpack(bytes).unwrap().as_number(),
+                number
+            );
+        }
+    }
+
+    #[test]
+    fn functions_have_no_msgpack_form() {
+        let chunk = crate::compiler::compile("fun f() {}").unwrap();
+        let function = chunk
+            .constants
+            .iter()
+            .find(|constant| constant.is_obj_type(ObjType::ObjFunction))
+            .unwrap();
+        assert!(function.serialize_msgpack().is_err());
+        assert!(list(vec![function.clone()]).serialize_msgpack().is_err());
+    }
+
+    #[test]
+    fn malformed_msgpack_is_an_error() {
+        assert!(Value::deserialize_msgpack(&[]).is_err());
+        assert!(Value::deserialize_msgpack(&[0xc0, 0xc0]).is_err());
+        assert!(Value::deserialize_msgpack(&[0xa3, b'a']).is_err());
+        assert!(Value::deserialize_msgpack(&[0xc1]).is_err());
+    }
+
+    #[test]
+    fn deeply_nested_msgpack_is_an_error() {
+        let mut bytes = vec![0x91; MSGPACK_MAX_DEPTH];
+        bytes.push(0xc0);
+        assert!(Value::deserialize_msgpack(&bytes).is_ok());
+
+        bytes.insert(0, 0x91);
+        let error = Value::deserialize_msgpack(&bytes).unwrap_err();
+        assert_eq!(error, "Nesting too deep.");
+        assert!(Value::deserialize_msgpack(&[0x91; 1_000_000]).is_err());
+    }
+
+    #[test]
+    fn accessors_read_their_own_variant() {
+        assert!(Value::from_bool(true).as_bool());
+        assert!(Value::from_nil().is_nil());
+        assert_eq!(Value::from_number(-2.5).as_number(), -2.5);
+        assert_eq!(Value::from_string("hi".to_string()).as_string(), "hi");
+        assert!(Value::from_string(String::new()).as_obj().is_some());
+        assert_eq!(Value::from_number(-7.0).as_integer(), -7);
+    }
+
+    #[test]
+    fn accessors_give_neutral_values_for_other_variants() {
+        let string = Value::from_string("1".to_string());
+        assert!(!Value::from_number(1.0).as_bool());
+        assert_eq!(Value::TRUE.as_number(), 0.0);
+        assert_eq!(string.as_number(), 0.0);
+        assert!(Value::NIL.as_obj().is_none());
+        assert_eq!(Value::from_number(1.0).as_string(), "");
+        assert_eq!(list(vec![]).as_string(), "");
+    }
+
+    #[test]
+    fn type_checks_match_the_variant() {
+        let string = Value::from_string("s".to_string());
+        assert!(string.is_string() && !string.is_function() && !string.is_number());
+        assert!(Value::FALSE.is_bool() && !Value::FALSE.is_nil());
+        assert!(Value::from_number(3.0).is_integer());
+        assert!(!Value::from_number(3.5).is_integer());
+        assert!(!string.is_integer());
+    }
+
+    #[test]
+    fn integers_end_at_2_pow_53() {
+        let limit = 2.0_f64.powi(53);
+        for number in [limit - 1.0, -(limit - 1.0)] {
+            assert!(Value::from_number(number).is_integer());
+            assert_eq!(Value::from_number(number).as_integer(), number as i64);
         }
+        for number in [limit, -limit, limit + 2.0, f64::INFINITY, f64::NAN] {
+            assert!(!Value::from_number(number).is_integer());
+        }
+    }
+
+    #[test]
+    fn as_integer_gives_0_for_non_integers() {
+        assert_eq!(Value::from_number(0.5).as_integer(), 0);
+        assert_eq!(Value::from_number(2.0_f64.powi(53)).as_integer(), 0);
+        assert_eq!(Value::TRUE.as_integer(), 0);
+    }
+
+    #[test]
+    fn type_names_are_what_typeof_reports() {
+        assert_eq!(Value::from_number(1.5).type_name(), "number");
+        assert_eq!(Value::from_bool(true).type_name(), "bool");
+        assert_eq!(Value::from_nil().type_name(), "nil");
+        assert_eq!(Value::from_string("a".to_string()).type_name(), "string");
+    }
+
+    #[test]
+    fn canonical_numbers_are_stable() {
+        let cases: [(f64, &str); 10] = [
+            (3.0, "3"),
+            (-0.0, "0"),
+            (0.1 + 0.2, "0.30000000000000004"),
+            (2.5, "2.5"),
+            (-12.75, "-12.75"),
+            (1e21, "1e21"),
+            (123456789.0, "123456789"),
+            (1e-8, "1e-8"),
+            (f64::INFINITY, "inf"),
+            (f64::NAN, "nan"),
+        ];
+        for (number, expected) in cases {
+            assert_eq!(Value::from_number(number).to_canonical_string(), expected);
+        }
+        assert_eq!(Value::from_bool(true).to_canonical_string(), "true");
+        assert_eq!(Value::nil().to_canonical_string(), "nil");
+    }
+
+    #[test]
+    fn singletons_match_fresh_values() {
+        assert!(Value::NIL.is_nil());
+        assert_eq!(describe(&Value::NIL), describe(&Value::from_nil()));
+        assert_eq!(describe(&Value::nil()), describe(&Value::from_nil()));
+        assert_eq!(describe(&Value::TRUE), describe(&Value::from_bool(true)));
+        assert_eq!(describe(&Value::FALSE), describe(&Value::from_bool(false)));
     }
 }
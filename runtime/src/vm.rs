@@ -1,103 +1,610 @@
-use std::collections::VecDeque;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::Duration;
 
-use crate::compiler::Compiler;
+use crate::compiler::compile;
+use crate::lox::LoxError;
 
 use crate::chunk::{byte_to_op, Chunk, OpCode};
 use crate::common::DEBUG_TRACE_EXECUTION;
-use crate::value::{Value, ValueType};
+use crate::object::{NativeError, NativeFn, Obj, ObjFunction, ObjNative, ObjType};
+use crate::value::{NumberFormat, Value, ValueFormat, ValueType};
 
+#[derive(Debug)]
 pub enum InterpretResult {
     InterpretCompileError,
     InterpretRuntimeError,
+    // A non-integer operand to an integer operation on `line`.
+    InterpretTypeMismatch { line: i32 },
+    // `/` or `%` with a zero divisor on `line`.
+    InterpretDivisionByZero { line: i32 },
+    InterpretExecutionLimitExceeded,
+    InterpretTimeout,
+    // The script called `exit` with this code.
+    InterpretExit(i32),
+}
+
+// What the last runtime error said and the line it happened on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub line: i32,
+}
+
+impl std::fmt::Display for InterpretResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InterpretResult::InterpretCompileError => return write!(f, "compilation error"),
+            InterpretResult::InterpretRuntimeError => return write!(f, "runtime error"),
+            InterpretResult::InterpretTypeMismatch { line } => {
+                return write!(f, "type mismatch on line {}", line)
+            }
+            InterpretResult::InterpretDivisionByZero { line } => {
+                return write!(f, "division by zero on line {}", line)
+            }
+            InterpretResult::InterpretExecutionLimitExceeded => {
+                return write!(f, "execution limit exceeded")
+            }
+            InterpretResult::InterpretTimeout => return write!(f, "timed out"),
+            InterpretResult::InterpretExit(code) => return write!(f, "exited with code {}", code),
+        }
+    }
+}
+
+impl std::error::Error for InterpretResult {}
+
+#[derive(Debug)]
+pub enum StepResult {
+    Continue,
+    Returned(Value),
+    // Reserved for instructions that suspend execution without finishing.
+    Yielded,
+    // `run` stopped at a breakpoint before executing the instruction there.
+    Paused(usize),
+}
+
+// Longest list a range may build.
+const RANGE_MAX: i64 = 1 << 24;
+
+const FRAMES_MAX: usize = 64;
+// Instructions between checks of the execution limit.
+const LIMIT_CHECK_INTERVAL: u64 = 1024;
+
+// Natives every vm gets.
+const NATIVES: [(&str, u8, NativeFn); 2] = [("sqrt", 1, native_sqrt), ("assert", 1, native_assert)];
+
+// Natives that reach outside the vm, left out of sandboxed vms.
+const HOST_NATIVES: [(&str, u8, NativeFn); 2] =
+    [("exit", 1, native_exit), ("getenv", 1, native_getenv)];
+
+fn native_sqrt(args: &[Value]) -> Result<Value, NativeError> {
+    if !args[0].is_number() {
+        return Err(NativeError::Error(
+            "Argument to 'sqrt' must be a number.".to_string(),
+        ));
+    }
+    return Ok(Value::from_number(args[0].as_number().sqrt()));
+}
+
+// Fails the script when `args[0]` is falsey, test files are built on it.
+fn native_assert(args: &[Value]) -> Result<Value, NativeError> {
+    if args[0].is_nil() || (args[0].is_bool() && !args[0].as_bool()) {
+        return Err(NativeError::Error("Assertion failed.".to_string()));
+    }
+    return Ok(Value::NIL);
+}
+
+// Whether a vm with the stdlib defines a native called `name`.
+pub fn is_native(name: &str) -> bool {
+    return NATIVES
+        .iter()
+        .chain(&HOST_NATIVES)
+        .any(|(native, _, _)| *native == name);
+}
+
+fn is_host_native(name: &str) -> bool {
+    return HOST_NATIVES
+        .iter()
+        .any(|(host_native, _, _)| *host_native == name);
+}
+
+fn native_exit(args: &[Value]) -> Result<Value, NativeError> {
+    let code = args[0].as_number();
+    if !args[0].is_integer() || code < i32::MIN as f64 || code > i32::MAX as f64 {
+        return Err(NativeError::Error(
+            "Argument to 'exit' must be an integer.".to_string(),
+        ));
+    }
+    return Err(NativeError::Exit(code as i32));
+}
+
+fn native_getenv(args: &[Value]) -> Result<Value, NativeError> {
+    if !args[0].is_string() {
+        return Err(NativeError::Error(
+            "Argument to 'getenv' must be a string.".to_string(),
+        ));
+    }
+    match std::env::var(args[0].as_string()) {
+        Ok(value) => return Ok(Value::from_string(value)),
+        Err(_) => return Ok(Value::NIL),
+    }
+}
+
+// Called with the previous value, if the global had one, and the new value.
+pub type GlobalWatcher = Box<dyn FnMut(Option<&Value>, &Value)>;
+
+// Closures can't derive Debug, so only the watched names are shown.
+#[derive(Default)]
+struct GlobalWatchers(HashMap<String, Vec<GlobalWatcher>>);
+
+impl std::fmt::Debug for GlobalWatchers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return f.debug_set().entries(self.0.keys()).finish();
+    }
+}
+
+// Where `print` and `write` go, stdout unless an embedder set a writer.
+#[derive(Default, Clone)]
+struct Output(Option<Rc<RefCell<dyn Write>>>);
+
+impl std::fmt::Debug for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(_) => return write!(f, "Output(writer)"),
+            None => return write!(f, "Output(stdout)"),
+        }
+    }
+}
+
+// Thread started when a run begins under a timeout, dropping `cancel` wakes
+// it up early.
+#[derive(Debug)]
+struct Timer {
+    cancel: mpsc::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+// State of a caller, restored when the function it called returns.
+#[derive(Debug, Clone)]
+struct CallFrame {
+    chunk: Option<Rc<Chunk>>,
+    function: Option<Rc<Obj>>,
+    ip: usize,
+    instruction_start: usize,
+    slots: usize,
+    #[cfg(feature = "unchecked")]
+    verified: bool,
+    scope_base: usize,
 }
 
 #[derive(Debug)]
 pub struct Vm {
-    chunk: Option<Chunk>,
+    chunk: Option<Rc<Chunk>>,
+    // Function being executed, `None` for the top level script.
+    function: Option<Rc<Obj>>,
+    frames: Vec<CallFrame>,
     stack: VecDeque<Value>,
     ip: usize,
+    last_error: Option<RuntimeError>,
+    // Offset of the instruction currently being executed.
+    instruction_start: usize,
+    // Stack index, from the bottom, of the current function's slot 0.
+    slots: usize,
+    // Stack length when each open block was entered, innermost last.
+    scopes: Vec<usize>,
+    // Scopes opened by callers, the current function's start after these.
+    scope_base: usize,
+    // Whether the running chunk passed `Chunk::verify`.
+    #[cfg(feature = "unchecked")]
+    verified: bool,
+    // Functions whose chunk and entry points passed along with a loaded
+    // chunk. A weak reference keeps the allocation, and so the address, from
+    // being reused without keeping the function alive.
+    #[cfg(feature = "unchecked")]
+    verified_functions: HashMap<*const Obj, std::rc::Weak<Obj>>,
+    strings: HashMap<String, Rc<Obj>>,
+    globals: HashMap<String, Value>,
+    value_format: ValueFormat,
+    breakpoints: HashSet<usize>,
+    watchers: GlobalWatchers,
+    // Breakpoint `run` last stopped at, skipped once when it resumes.
+    paused_at: Option<usize>,
+    // Instructions executed by `run` since the current chunk was loaded.
+    instruction_count: u64,
+    max_instructions: Option<u64>,
+    timeout: Option<Duration>,
+    // Set by the timer thread once the timeout passes.
+    interrupted: Arc<AtomicBool>,
+    timer: Option<Timer>,
+    sandboxed: bool,
+    // Whether the natives every vm gets are defined.
+    stdlib: bool,
+    output: Output,
+    // Approximate size of the values on the stack.
+    bytes_allocated: usize,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+// Snapshot of the vm's state. Chunks and objects are never mutated, so
+// sharing them is as good as a copy. Watchers are closures and aren't
+// carried over.
+impl Clone for Vm {
+    fn clone(&self) -> Self {
+        Self {
+            chunk: self.chunk.clone(),
+            function: self.function.clone(),
+            frames: self.frames.clone(),
+            stack: self.stack.clone(),
+            ip: self.ip,
+            instruction_start: self.instruction_start,
+            slots: self.slots,
+            scopes: self.scopes.clone(),
+            scope_base: self.scope_base,
+            #[cfg(feature = "unchecked")]
+            verified: self.verified,
+            #[cfg(feature = "unchecked")]
+            verified_functions: self.verified_functions.clone(),
+            strings: self.strings.clone(),
+            globals: self.globals.clone(),
+            value_format: self.value_format,
+            breakpoints: self.breakpoints.clone(),
+            watchers: GlobalWatchers::default(),
+            paused_at: self.paused_at,
+            last_error: self.last_error.clone(),
+            instruction_count: self.instruction_count,
+            max_instructions: self.max_instructions,
+            timeout: self.timeout,
+            interrupted: Arc::new(AtomicBool::new(false)),
+            timer: None,
+            sandboxed: self.sandboxed,
+            stdlib: self.stdlib,
+            output: self.output.clone(),
+            bytes_allocated: self.bytes_allocated,
+        }
+    }
 }
 
 impl Vm {
     pub fn new() -> Self {
-        Self {
+        let mut vm = Self {
             chunk: None,
+            function: None,
+            frames: vec![],
             stack: VecDeque::new(),
             ip: 0,
+            last_error: None,
+            instruction_start: 0,
+            slots: 0,
+            scopes: vec![],
+            scope_base: 0,
+            #[cfg(feature = "unchecked")]
+            verified: false,
+            #[cfg(feature = "unchecked")]
+            verified_functions: HashMap::new(),
+            strings: HashMap::new(),
+            globals: HashMap::new(),
+            value_format: ValueFormat::default(),
+            breakpoints: HashSet::new(),
+            watchers: GlobalWatchers::default(),
+            paused_at: None,
+            instruction_count: 0,
+            max_instructions: None,
+            timeout: None,
+            interrupted: Arc::new(AtomicBool::new(false)),
+            timer: None,
+            sandboxed: false,
+            stdlib: true,
+            output: Output::default(),
+            bytes_allocated: 0,
+        };
+        vm.define_natives();
+        return vm;
+    }
+
+    // A vm for untrusted scripts, see `with_sandbox`.
+    pub fn sandboxed() -> Self {
+        return Self::new().with_sandbox();
+    }
+
+    // Leaves out natives with access to the host, like `getenv`, for
+    // embedders running untrusted scripts.
+    pub fn with_sandbox(mut self) -> Self {
+        self.sandboxed = true;
+        for (name, _, _) in HOST_NATIVES {
+            self.globals.remove(name);
         }
+        return self;
     }
 
-    pub fn interpret_source(&mut self, source: String) -> Result<(), InterpretResult> {
-        self.reset_stack();
-        let mut compiler = Compiler::new(source);
-        let chunk = Chunk::new();
+    // Without the stdlib no natives are defined, sandboxed or not.
+    pub fn with_stdlib(mut self, stdlib: bool) -> Self {
+        self.stdlib = stdlib;
+        for (name, _, _) in NATIVES.iter().chain(&HOST_NATIVES) {
+            self.globals.remove(*name);
+        }
+        self.define_natives();
+        return self;
+    }
+
+    // Sends what `print` and `write` produce to `output` instead of stdout.
+    // Runtime errors and debug traces still go to stdout.
+    pub fn with_output(mut self, output: impl Write + 'static) -> Self {
+        self.output = Output(Some(Rc::new(RefCell::new(output))));
+        return self;
+    }
+
+    // Stops any script that runs more than `max_instructions` instructions,
+    // checked every `LIMIT_CHECK_INTERVAL` instructions.
+    pub fn with_max_instructions(mut self, max_instructions: u64) -> Self {
+        self.max_instructions = Some(max_instructions);
+        return self;
+    }
+
+    // Stops every following script once `duration` of wall clock time has
+    // passed since it started, checked as often as the instruction limit.
+    // Replaces any earlier timeout.
+    pub fn set_timeout(&mut self, duration: Duration) {
+        self.timeout = Some(duration);
+    }
+
+    pub fn cancel_timeout(&mut self) {
+        self.timeout = None;
+        self.stop_timer();
+    }
 
-        match compiler.to_chunk(chunk) {
-            Some(chunk) => self.chunk = Some(chunk),
-            None => return Err(InterpretResult::InterpretCompileError),
+    fn start_timer(&mut self) {
+        let duration = match self.timeout {
+            Some(duration) => duration,
+            None => return,
         };
+        let (cancel, cancelled) = mpsc::channel();
+        let interrupted = Arc::clone(&self.interrupted);
+        let thread = std::thread::spawn(move || {
+            if let Err(mpsc::RecvTimeoutError::Timeout) = cancelled.recv_timeout(duration) {
+                interrupted.store(true, Ordering::Relaxed);
+            }
+        });
+        self.timer = Some(Timer { cancel, thread });
+    }
 
-        self.ip = 0;
+    // Joins the timer before clearing `interrupted`, so a timeout that fired
+    // during this run can't stop the next one.
+    fn stop_timer(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            drop(timer.cancel);
+            let _ = timer.thread.join();
+        }
+        self.interrupted.store(false, Ordering::Relaxed);
+    }
 
-        let result = self.run();
-        return result;
+    pub fn set_number_format(&mut self, number_format: NumberFormat) {
+        self.value_format.number_format = number_format;
     }
 
-    pub fn interpret_op_code(&mut self, op_code: Vec<u8>) -> Result<(), InterpretResult> {
+    pub fn set_value_format(&mut self, value_format: ValueFormat) {
+        self.value_format = value_format;
+    }
+
+    // Drops the state of the previous run but keeps what outlives a single
+    // script, like globals and the intern table, so a REPL can reuse one vm.
+    pub fn reset_for_next(&mut self) {
         self.reset_stack();
-        let mut chunk = Chunk::new();
+        self.chunk = None;
+        self.function = None;
+        self.ip = 0;
+        self.instruction_start = 0;
+        self.last_error = None;
+        self.slots = 0;
+        // Functions no run can reach anymore
+        #[cfg(feature = "unchecked")]
+        self.verified_functions
+            .retain(|_, function| function.strong_count() > 0);
+        self.paused_at = None;
+        self.instruction_count = 0;
+        self.stop_timer();
+    }
+
+    // Clean slate without building a new vm. Globals and interned strings
+    // only go when `clear_globals` is set.
+    pub fn reset(&mut self, clear_globals: bool) {
+        if clear_globals {
+            self.globals.clear();
+            self.strings.clear();
+            self.define_natives();
+        }
+        self.reset_for_next();
+        self.cancel_timeout();
+    }
 
-        let mut lines: Vec<i32> = vec![];
-        let mut instructions: Vec<u8> = vec![];
-        let mut previous: Option<u8> = None;
+    // Compile errors come back with their diagnostics for the caller to
+    // report, runtime errors are printed as they happen.
+    pub fn interpret_source(&mut self, source: String) -> Result<(), LoxError> {
+        match compile(&source) {
+            Ok(chunk) => return self.interpret_chunk(chunk).map_err(LoxError::Interpret),
+            Err(errors) => return Err(LoxError::Compile(errors)),
+        }
+    }
+
+    // Runs an already built chunk from its first instruction.
+    pub fn interpret_chunk(&mut self, chunk: Chunk) -> Result<(), InterpretResult> {
+        self.load_chunk(chunk)?;
+        self.run()?;
+        return Ok(());
+    }
 
-        for op in op_code {
-            match previous {
-                Some(instruction) => {
-                    instructions.push(instruction);
-                    lines.push(op.into());
-                    previous = None;
+    // Runs `bytes` as bare code with no constants or line info. Anything
+    // that fails `Chunk::verify` is rejected before running, so no input
+    // can make the vm panic.
+    pub fn run_bytes(&mut self, bytes: &[u8]) -> Result<(), InterpretResult> {
+        let chunk = Chunk::from_code(bytes.to_vec());
+        if chunk.verify().is_err() {
+            return Err(InterpretResult::InterpretCompileError);
+        }
+        return self.interpret_chunk(chunk);
+    }
+
+    // Prepares `chunk` to run without executing anything, so a driver can
+    // go through it with `step`. Chunks are checked once here, compiler bugs
+    // and corrupt bins are rejected before they run.
+    pub fn load_chunk(&mut self, mut chunk: Chunk) -> Result<(), InterpretResult> {
+        self.reset_for_next();
+        self.intern_constants(&mut chunk);
+        if let Err(error) = chunk.verify_integrity() {
+            self.runtime_error(format!("Invalid chunk: {}", error));
+            return Err(InterpretResult::InterpretRuntimeError);
+        }
+        #[cfg(feature = "unchecked")]
+        {
+            self.verified = true;
+            self.add_verified_functions(&chunk);
+        }
+        self.chunk = Some(Rc::new(chunk));
+        return Ok(());
+    }
+
+    // Offset of the next instruction in the chunk being executed.
+    pub fn ip(&self) -> usize {
+        return self.ip;
+    }
+
+    #[cfg(feature = "unchecked")]
+    fn add_verified_functions(&mut self, chunk: &Chunk) {
+        for constant in &chunk.constants {
+            if let Some(obj) = constant.as_obj() {
+                if let Obj::ObjFunction(function) = &**obj {
+                    self.add_verified_functions(&function.chunk);
+                    self.verified_functions
+                        .insert(Rc::as_ptr(obj), Rc::downgrade(obj));
                 }
-                None => previous = Some(op),
             }
         }
+    }
+
+    // Functions can come from chunks that were never verified, like the
+    // ones handed to `define_global`, so this is looked up on every call.
+    #[cfg(feature = "unchecked")]
+    fn is_verified(&self, function: &Rc<Obj>) -> bool {
+        return self.verified_functions.contains_key(&Rc::as_ptr(function));
+    }
 
-        let mut i = 0;
+    pub fn interpret_op_code(&mut self, op_code: Vec<u8>) -> Result<(), InterpretResult> {
+        match Chunk::from_bin(&op_code) {
+            Ok(chunk) => return self.interpret_chunk(chunk),
+            Err(message) => {
+                println!("{}", message);
+                return Err(InterpretResult::InterpretCompileError);
+            }
+        }
+    }
+
+    // Breakpoints are offsets into whichever chunk is executing, they stay
+    // set until cleared.
+    pub fn set_breakpoint(&mut self, offset: usize) {
+        self.breakpoints.insert(offset);
+    }
+
+    pub fn clear_breakpoint(&mut self, offset: usize) {
+        self.breakpoints.remove(&offset);
+    }
+
+    // `callback` fires every time the global `name` is defined or assigned.
+    pub fn watch_global(&mut self, name: &str, callback: GlobalWatcher) {
+        self.watchers
+            .0
+            .entry(name.to_string())
+            .or_default()
+            .push(callback);
+    }
+
+    // Like `watch_global` for callers that only need the new value.
+    pub fn watch(&mut self, name: &str, callback: Box<dyn Fn(&Value)>) {
+        self.watch_global(name, Box::new(move |_, value| callback(value)));
+    }
+
+    // Drops every watcher of the global `name`.
+    pub fn unwatch(&mut self, name: &str) {
+        self.watchers.0.remove(name);
+    }
+
+    // Runs until the script returns or reaches a breakpoint. Calling it again
+    // after a pause resumes from the breakpoint.
+    pub fn run(&mut self) -> Result<StepResult, InterpretResult> {
+        if self.instruction_count == 0 && self.timer.is_none() {
+            self.start_timer();
+        }
         loop {
-            if i == instructions.len() {
-                break;
+            if self.breakpoints.contains(&self.ip) && self.paused_at.take() != Some(self.ip) {
+                self.paused_at = Some(self.ip);
+                return Ok(StepResult::Paused(self.ip));
             }
+            self.paused_at = None;
 
-            let current = instructions[i];
-            match current {
-                1 => {
-                    if let Some(next) = instructions.get(i + 1) {
-                        let constant = chunk.add_constant(Value::from_number(f64::from(*next)));
-                        chunk.write_instruction(OpCode::OpConstant, lines[i]);
-                        chunk.write_byte(constant, lines[i + 1]);
-                        i += 1;
+            self.instruction_count += 1;
+            if self.instruction_count.is_multiple_of(LIMIT_CHECK_INTERVAL) {
+                if let Some(max_instructions) = self.max_instructions {
+                    if self.instruction_count > max_instructions {
+                        self.runtime_error("Execution limit exceeded.".to_string());
+                        self.stop_timer();
+                        return Err(InterpretResult::InterpretExecutionLimitExceeded);
                     }
                 }
-                _ => chunk.write_byte(current, lines[i]),
+                if self.interrupted.load(Ordering::Relaxed) {
+                    self.runtime_error("Execution timed out.".to_string());
+                    self.stop_timer();
+                    return Err(InterpretResult::InterpretTimeout);
+                }
             }
 
-            i += 1;
+            match self.step() {
+                Ok(StepResult::Continue | StepResult::Yielded | StepResult::Paused(_)) => (),
+                Ok(StepResult::Returned(value)) => {
+                    self.stop_timer();
+                    return Ok(StepResult::Returned(value));
+                }
+                Err(error) => {
+                    self.stop_timer();
+                    return Err(error);
+                }
+            }
         }
+    }
 
-        self.chunk = Some(chunk);
-        self.ip = 0;
-
-        self.run()
+    // Executes the instruction at `ip` and reports whether execution can go on.
+    pub fn step(&mut self) -> Result<StepResult, InterpretResult> {
+        let result = self.execute_instruction();
+        // A failed instruction can leave `ip` inside its operands, whatever
+        // runs after it is bounds checked.
+        #[cfg(feature = "unchecked")]
+        if result.is_err() {
+            self.verified = false;
+        }
+        return result;
     }
 
-    pub fn run(&mut self) -> Result<(), InterpretResult> {
-        macro_rules! binary_operation {
-            ($value_type: expr, $op: tt) => {
+    fn execute_instruction(&mut self) -> Result<StepResult, InterpretResult> {
+        // `$error` is what failing the check returns, a runtime error unless
+        // given.
+        macro_rules! check_operands {
+            ($check: ident, $message: expr) => {
+                check_operands!($check, $message, InterpretResult::InterpretRuntimeError)
+            };
+            ($check: ident, $message: expr, $error: expr) => {
                 match (self.peek_stack(0), self.peek_stack(1)) {
                     (Some(a), Some(b)) => {
-                        if !a.is_number() || !b.is_number() {
-                            self.runtime_error("Operands must be numbers.".to_string());
-                            return Err(InterpretResult::InterpretRuntimeError);
+                        if !a.$check() || !b.$check() {
+                            let error = $error;
+                            self.runtime_error($message.to_string());
+                            return Err(error);
                         }
                     }
                     _ => {
@@ -105,6 +612,12 @@ impl Vm {
                         return Err(InterpretResult::InterpretRuntimeError);
                     }
                 }
+            };
+        }
+
+        macro_rules! binary_operation {
+            ($value_type: expr, $op: tt) => {
+                check_operands!(is_number, "Operands must be numbers.");
 
                 if let Some(a) = self.pop_stack() {
                     if let Some(b) = self.pop_stack() {
@@ -114,118 +627,698 @@ impl Vm {
             };
         }
 
-        let mut offset = 0;
+        // Like `binary_operation`, but a zero right operand is an error
+        // instead of giving inf or NaN.
+        macro_rules! division_operation {
+            ($op: tt, $message: expr) => {
+                check_operands!(is_number, "Operands must be numbers.");
 
-        loop {
-            if DEBUG_TRACE_EXECUTION {
-                print!("          ");
-                for value in &self.stack {
-                    print!("[");
-                    value.print();
-                    print!("]");
-                }
-                println!();
-
-                if let Some(chunk) = &self.chunk {
-                    match chunk.dissasemble_instruction(offset) {
-                        Ok(new_offset) => offset = new_offset,
-                        Err(err) => {
-                            println!("{}", err);
+                if self
+                    .peek_stack(0)
+                    .is_some_and(|divisor| divisor.as_number() == 0.0)
+                {
+                    let line = self.current_line();
+                    self.runtime_error($message.to_string());
+                    return Err(InterpretResult::InterpretDivisionByZero { line });
+                }
+                binary_operation!(Value::from_number, $op);
+            };
+        }
+
+        // Shifts work on integer operands, the shift amount wraps around
+        // modulo 64.
+        macro_rules! shift_operation {
+            ($shift: expr) => {
+                check_operands!(
+                    is_integer,
+                    "Operand must be an integer.",
+                    InterpretResult::InterpretTypeMismatch {
+                        line: self.current_line()
+                    }
+                );
+
+                if let Some(a) = self.pop_stack() {
+                    if let Some(b) = self.pop_stack() {
+                        let amount = a.as_integer().rem_euclid(64) as u32;
+                        let shifted: f64 = $shift(b.as_integer(), amount);
+                        self.push_stack(Value::from_number(shifted));
+                    }
+                }
+            };
+        }
+
+        // Builds the list of consecutive numbers from the lower bound up to
+        // the upper one, a lower bound past the upper one gives an empty list.
+        // `$past_end` is how far past `end` the range goes, one for `..=`.
+        macro_rules! range_operation {
+            ($past_end: expr) => {
+                check_operands!(
+                    is_integer,
+                    "Operand must be an integer.",
+                    InterpretResult::InterpretTypeMismatch {
+                        line: self.current_line()
+                    }
+                );
+
+                if let Some(end) = self.pop_stack() {
+                    if let Some(start) = self.pop_stack() {
+                        let start = start.as_integer();
+                        let length = (end.as_integer() + $past_end - start).max(0);
+                        if length > RANGE_MAX {
+                            self.runtime_error("Range too long.".to_string());
                             return Err(InterpretResult::InterpretRuntimeError);
                         }
+                        let elements = (start..start + length)
+                            .map(|current| Value::from_number(current as f64))
+                            .collect();
+                        self.push_stack(Value::from_obj(Rc::new(Obj::ObjList(elements))));
                     }
-                };
+                }
+            };
+        }
+
+        if DEBUG_TRACE_EXECUTION {
+            print!("          ");
+            for value in &self.stack {
+                print!("[{}]", value.display_with_format(&self.value_format));
             }
+            println!();
 
-            let instruction = self.read_byte()?;
-            match byte_to_op(instruction) {
-                Ok(operation) => match operation {
-                    OpCode::OpReturn => {
-                        if let Some(value) = self.pop_stack() {
-                            value.print();
-                            println!()
-                        }
+            if let Some(chunk) = &self.chunk {
+                if let Err(err) = chunk.dissasemble_instruction(self.ip) {
+                    println!("{}", err);
+                    return Err(InterpretResult::InterpretRuntimeError);
+                }
+            };
+        }
+
+        self.instruction_start = self.ip;
+        let instruction = self.read_op_code()?;
+        match byte_to_op(instruction) {
+            Ok(operation) => match operation {
+                OpCode::OpReturn => {
+                    let result = self.pop_stack().unwrap_or(Value::NIL);
+                    match self.frames.pop() {
+                        Some(frame) => {
+                            // Discards the callee, its arguments and locals
+                            let count = self.stack.len().saturating_sub(self.slots);
+                            self.drain_stack(..count);
+                            // Blocks the callee returned from inside
+                            self.scopes.truncate(self.scope_base);
 
-                        return Ok(());
+                            self.chunk = frame.chunk;
+                            self.function = frame.function;
+                            self.ip = frame.ip;
+                            self.instruction_start = frame.instruction_start;
+                            self.slots = frame.slots;
+                            #[cfg(feature = "unchecked")]
+                            {
+                                self.verified = frame.verified;
+                            }
+                            self.scope_base = frame.scope_base;
+                            self.push_stack(result);
+                        }
+                        None => return Ok(StepResult::Returned(result)),
                     }
-                    OpCode::OpConstant => {
-                        let constant = self.read_constant()?;
-                        self.push_stack(constant);
+                }
+                OpCode::OpTailCall => {
+                    let arg_count = self.read_byte()? as usize;
+                    match self.peek_stack(arg_count).cloned() {
+                        Some(callee) => self.call_value(callee, arg_count, true)?,
+                        None => {
+                            self.runtime_error("Callee missing.".to_string());
+                            return Err(InterpretResult::InterpretRuntimeError);
+                        }
                     }
-                    OpCode::OpNil => self.push_stack(Value::from_nil()),
-                    OpCode::OpTrue => self.push_stack(Value::from_bool(true)),
-                    OpCode::OpFalse => {
-                        self.push_stack(Value::from_bool(false));
+                }
+                // The global may have been redefined, anything but a native
+                // goes through the generic call.
+                OpCode::OpCallNative => {
+                    let arg_count = self.read_byte()? as usize;
+                    let callee = match self.peek_stack(arg_count).and_then(Value::as_obj) {
+                        Some(obj) => Rc::clone(obj),
+                        None => {
+                            self.runtime_error("Can only call functions.".to_string());
+                            return Err(InterpretResult::InterpretRuntimeError);
+                        }
+                    };
+                    match &*callee {
+                        Obj::ObjNative(native) => self.call_native(native, arg_count)?,
+                        _ => self.call_value(Value::from_obj(callee), arg_count, false)?,
                     }
-                    OpCode::OpNegate => {
-                        if let Some(value) = self.peek_stack(0) {
-                            if !value.is_number() {
-                                self.runtime_error("Operand must be number.".to_string());
-                                return Err(InterpretResult::InterpretRuntimeError);
-                            }
-
-                            if let Some(value) = &self.pop_stack() {
-                                self.push_stack(Value::from_number(-value.as_number()));
-                            }
+                }
+                OpCode::OpCall => {
+                    let arg_count = self.read_byte()? as usize;
+                    match self.peek_stack(arg_count).cloned() {
+                        Some(callee) => self.call_value(callee, arg_count, false)?,
+                        None => {
+                            self.runtime_error("Callee missing.".to_string());
+                            return Err(InterpretResult::InterpretRuntimeError);
+                        }
+                    }
+                }
+                OpCode::OpConstant => {
+                    let constant = self.read_constant()?;
+                    self.push_stack(constant);
+                }
+                OpCode::OpConstantLong => {
+                    let constant = self.read_constant_long()?;
+                    self.push_stack(constant);
+                }
+                OpCode::OpAssertType => {
+                    let tag = self.read_byte()?;
+                    let expected = match ValueType::from_tag(tag) {
+                        Some(expected) => expected,
+                        None => {
+                            self.runtime_error(format!("Unknown type tag {}.", tag));
+                            return Err(InterpretResult::InterpretRuntimeError);
                         }
+                    };
+                    let actual = self.peek_stack(0).map(|value| value.get_type().clone());
+                    if actual.as_ref() != Some(&expected) {
+                        let actual = actual.map_or("nothing", |actual| actual.name());
+                        self.runtime_error(format!(
+                            "Expected {} on top of the stack but got {}.",
+                            expected.name(),
+                            actual
+                        ));
+                        return Err(InterpretResult::InterpretRuntimeError);
+                    }
+                }
+                OpCode::OpPop => {
+                    self.pop_stack();
+                }
+                OpCode::OpPopN => {
+                    let count = (self.read_byte()? as usize).min(self.stack.len());
+                    self.drain_stack(..count);
+                }
+                OpCode::OpPushFrame => self.scopes.push(self.stack.len()),
+                OpCode::OpPopFrame => {
+                    if self.scopes.len() <= self.scope_base {
+                        self.runtime_error("Scope frame missing.".to_string());
+                        return Err(InterpretResult::InterpretRuntimeError);
                     }
-                    OpCode::OpNot => {
-                        if let Some(value) = &self.pop_stack() {
-                            self.push_stack(Value::from_bool(self.is_falsey(value)));
+                    let depth = self.scopes.pop().unwrap_or(0);
+                    let count = self.stack.len().saturating_sub(depth);
+                    self.drain_stack(..count);
+                }
+                OpCode::OpDefineGlobal | OpCode::OpDefineGlobalLong => {
+                    let name = self.read_global_name(operation)?;
+                    let value = self.peek_stack(0).cloned().unwrap_or(Value::NIL);
+                    self.set_global(name.as_string(), value);
+                    self.pop_stack();
+                }
+                OpCode::OpGetGlobal | OpCode::OpGetGlobalLong => {
+                    let name = self.read_global_name(operation)?;
+                    match self.globals.get(name.as_string()) {
+                        Some(value) => {
+                            let value = value.clone();
+                            self.push_stack(value);
+                        }
+                        None if self.sandboxed && is_host_native(name.as_string()) => {
+                            self.runtime_error(
+                                "Operation not permitted in sandbox mode.".to_string(),
+                            );
+                            return Err(InterpretResult::InterpretRuntimeError);
+                        }
+                        None => {
+                            self.runtime_error(format!(
+                                "Undefined variable '{}'.",
+                                name.as_string()
+                            ));
+                            return Err(InterpretResult::InterpretRuntimeError);
                         }
                     }
-                    OpCode::OpAdd => {
-                        binary_operation!(Value::from_number, +);
+                }
+                OpCode::OpSetGlobal | OpCode::OpSetGlobalLong => {
+                    let name = self.read_global_name(operation)?;
+                    // Assigning never defines, the global has to exist already
+                    if !self.globals.contains_key(name.as_string()) {
+                        self.runtime_error(format!("Undefined variable '{}'.", name.as_string()));
+                        return Err(InterpretResult::InterpretRuntimeError);
                     }
-                    OpCode::OpSubtract => {
-                        binary_operation!(Value::from_number, -);
+                    let value = self.peek_stack(0).cloned().unwrap_or(Value::NIL);
+                    self.set_global(name.as_string(), value);
+                }
+                OpCode::OpGetLocal => {
+                    let slot = self.read_byte()? as usize;
+                    match self.local_slot(slot) {
+                        Some(value) => {
+                            let value = value.clone();
+                            self.push_stack(value);
+                        }
+                        None => {
+                            self.runtime_error("Local slot out of bounds.".to_string());
+                            return Err(InterpretResult::InterpretRuntimeError);
+                        }
                     }
-                    OpCode::OpMultiply => {
-                        binary_operation!(Value::from_number, *);
+                }
+                OpCode::OpSetLocal => {
+                    let slot = self.read_byte()? as usize;
+                    // Assignment is an expression, the value stays on the stack
+                    let value = self.peek_stack(0).cloned().unwrap_or(Value::NIL);
+                    let size = value.approximate_size_bytes();
+                    let replaced = match self.local_slot(slot) {
+                        Some(local) => std::mem::replace(local, value),
+                        None => {
+                            self.runtime_error("Local slot out of bounds.".to_string());
+                            return Err(InterpretResult::InterpretRuntimeError);
+                        }
+                    };
+                    self.bytes_allocated = (self.bytes_allocated + size)
+                        .saturating_sub(replaced.approximate_size_bytes());
+                }
+                OpCode::OpIncrement | OpCode::OpDecrement => {
+                    let slot = self.read_byte()? as usize;
+                    let step = match operation {
+                        OpCode::OpIncrement => 1.0,
+                        _ => -1.0,
+                    };
+                    match self.local_slot(slot) {
+                        Some(local) if local.is_number() => {
+                            *local = Value::from_number(local.as_number() + step);
+                        }
+                        Some(_) => {
+                            self.runtime_error("Operand must be number.".to_string());
+                            return Err(InterpretResult::InterpretRuntimeError);
+                        }
+                        None => {
+                            self.runtime_error("Local slot out of bounds.".to_string());
+                            return Err(InterpretResult::InterpretRuntimeError);
+                        }
                     }
-                    OpCode::OpDivide => {
-                        binary_operation!(Value::from_number, /);
+                }
+                OpCode::OpJump => {
+                    let offset = self.read_short()?;
+                    self.ip += offset as usize;
+                }
+                OpCode::OpJumpIfNil => {
+                    let offset = self.read_short()?;
+                    if self.peek_stack(0).is_some_and(|value| value.is_nil()) {
+                        self.ip += offset as usize;
                     }
-                    OpCode::OpGreater => {
-                        binary_operation!(Value::from_bool, >);
+                }
+                OpCode::OpJumpIfFalse => {
+                    let offset = self.read_short()?;
+                    if self
+                        .peek_stack(0)
+                        .is_some_and(|value| self.is_falsey(value))
+                    {
+                        self.ip += offset as usize;
                     }
-                    OpCode::OpLess => {
-                        binary_operation!(Value::from_bool, <);
+                }
+                OpCode::OpLoop => {
+                    let offset = self.read_short()?;
+                    self.ip -= offset as usize;
+                }
+                OpCode::OpPrint => {
+                    if let Some(value) = self.pop_stack() {
+                        let text = value.display_with_format(&self.value_format);
+                        self.write_output(&format!("{}\n", text));
                     }
-                    OpCode::OpEqual => {
-                        if let Some(a) = self.pop_stack() {
-                            if let Some(b) = self.pop_stack() {
-                                self.push_stack(Value::from_bool(self.values_equal(a, b)));
-                            }
+                }
+                // Values are printed in the order they were pushed.
+                OpCode::OpPrintMany => {
+                    let count = self.read_byte()? as usize;
+                    let mut texts = vec![];
+                    for _ in 0..count {
+                        if let Some(value) = self.pop_stack() {
+                            texts.push(value.display_with_format(&self.value_format));
+                        }
+                    }
+                    texts.reverse();
+                    self.write_output(&format!("{}\n", texts.join(" ")));
+                }
+                OpCode::OpWrite => {
+                    if let Some(value) = self.pop_stack() {
+                        let text = value.display_with_format(&self.value_format);
+                        self.write_output(&text);
+                    }
+                }
+                OpCode::OpNil => self.push_stack(Value::NIL),
+                OpCode::OpZero => self.push_stack(Value::from_number(0.0)),
+                OpCode::OpOne => self.push_stack(Value::from_number(1.0)),
+                OpCode::OpTrue => self.push_stack(Value::TRUE),
+                OpCode::OpFalse => self.push_stack(Value::FALSE),
+                OpCode::OpNegate => {
+                    if self.peek_stack(0).is_some_and(|value| !value.is_number()) {
+                        self.runtime_error("Operand must be number.".to_string());
+                        return Err(InterpretResult::InterpretRuntimeError);
+                    }
+
+                    // Negated in place rather than popped and pushed again
+                    if let Some(value) = self.peek_stack_mut(0) {
+                        *value = Value::from_number(-value.as_number());
+                    }
+                }
+                OpCode::OpNot => {
+                    if let Some(value) = &self.pop_stack() {
+                        self.push_stack(Value::from_bool(self.is_falsey(value)));
+                    }
+                }
+                OpCode::OpType => {
+                    if let Some(value) = &self.pop_stack() {
+                        let type_name = self.intern(value.type_name());
+                        self.push_stack(Value::from_obj(type_name));
+                    }
+                }
+                OpCode::OpAdd => {
+                    binary_operation!(Value::from_number, +);
+                }
+                OpCode::OpSubtract => {
+                    binary_operation!(Value::from_number, -);
+                }
+                OpCode::OpMultiply => {
+                    binary_operation!(Value::from_number, *);
+                }
+                OpCode::OpDivide => {
+                    division_operation!(/, "Division by zero.");
+                }
+                OpCode::OpModulo => {
+                    division_operation!(%, "Modulo by zero.");
+                }
+                OpCode::OpShiftLeft => {
+                    shift_operation!(|value: i64, amount| (value << amount) as f64);
+                }
+                OpCode::OpShiftRight => {
+                    shift_operation!(|value: i64, amount| (value >> amount) as f64);
+                }
+                OpCode::OpShiftRightLogical => {
+                    shift_operation!(|value: i64, amount| ((value as u64) >> amount) as f64);
+                }
+                OpCode::OpRange => {
+                    range_operation!(0);
+                }
+                OpCode::OpRangeInclusive => {
+                    range_operation!(1);
+                }
+                OpCode::OpGreater => {
+                    binary_operation!(Value::from_bool, >);
+                }
+                OpCode::OpLess => {
+                    binary_operation!(Value::from_bool, <);
+                }
+                OpCode::OpEqual => {
+                    if let Some(a) = self.pop_stack() {
+                        if let Some(b) = self.pop_stack() {
+                            self.push_stack(Value::from_bool(self.values_equal(a, b)));
                         }
                     }
-                },
-                Err(err) => {
-                    println!("{}", err);
-                    return Err(InterpretResult::InterpretRuntimeError);
                 }
+                OpCode::OpIs => {
+                    if let Some(a) = self.pop_stack() {
+                        if let Some(b) = self.pop_stack() {
+                            self.push_stack(Value::from_bool(self.values_identical(a, b)));
+                        }
+                    }
+                }
+                // Lists are the only containers, there are no maps to look
+                // keys up in.
+                OpCode::OpContains => {
+                    let container = self.pop_stack().unwrap_or(Value::NIL);
+                    let elements = match container.as_obj().map(|obj| &**obj) {
+                        Some(Obj::ObjList(elements)) => elements,
+                        _ => {
+                            self.runtime_error("Right operand of 'in' must be a list.".to_string());
+                            return Err(InterpretResult::InterpretRuntimeError);
+                        }
+                    };
+                    if let Some(item) = self.pop_stack() {
+                        let found = elements
+                            .iter()
+                            .any(|element| self.values_equal(item.clone(), element.clone()));
+                        self.push_stack(Value::from_bool(found));
+                    }
+                }
+            },
+            Err(err) => {
+                println!("{}", err);
+                return Err(InterpretResult::InterpretRuntimeError);
             }
         }
+
+        return Ok(StepResult::Continue);
     }
 
     pub fn push_stack(&mut self, value: Value) {
+        self.bytes_allocated += value.approximate_size_bytes();
         self.stack.push_front(value);
     }
 
     pub fn pop_stack(&mut self) -> Option<Value> {
-        return self.stack.pop_front();
+        let value = self.stack.pop_front()?;
+        self.bytes_allocated = self
+            .bytes_allocated
+            .saturating_sub(value.approximate_size_bytes());
+        return Some(value);
+    }
+
+    // Stack length at the start of each open block, outermost first, so a
+    // debugger can group the stack by scope.
+    pub fn inspect_scopes(&self) -> &[usize] {
+        return &self.scopes;
+    }
+
+    // Snapshot of the stack from bottom to top.
+    pub fn inspect_stack(&self) -> Vec<Value> {
+        return self.stack.iter().rev().cloned().collect();
+    }
+
+    // Approximate memory held by the stack, for embedders enforcing a limit.
+    pub fn bytes_allocated(&self) -> usize {
+        return self.bytes_allocated;
     }
 
     pub fn peek_stack(&self, distance: usize) -> Option<&Value> {
-        return self.stack.get(self.stack.len() - (distance + 1));
+        // The top of the stack is the front of the deque.
+        return self.stack.get(distance);
+    }
+
+    // A tail call replaces the current function's frame instead of pushing
+    // a new one, the top level script keeps its frame.
+    fn call_value(
+        &mut self,
+        callee: Value,
+        arg_count: usize,
+        tail: bool,
+    ) -> Result<(), InterpretResult> {
+        let obj = match callee.as_obj() {
+            Some(obj) => Rc::clone(obj),
+            None => {
+                self.runtime_error("Can only call functions.".to_string());
+                return Err(InterpretResult::InterpretRuntimeError);
+            }
+        };
+
+        match &*obj {
+            Obj::ObjFunction(function) => {
+                let (min_arity, arity) = (function.min_arity as usize, function.arity as usize);
+                if arg_count < min_arity || arg_count > arity {
+                    let expected = match min_arity == arity {
+                        true => arity.to_string(),
+                        false => format!("{} to {}", min_arity, arity),
+                    };
+                    self.runtime_error(format!(
+                        "Expected {} arguments but got {} in call to '{}'.",
+                        expected,
+                        arg_count,
+                        function.display_name()
+                    ));
+                    return Err(InterpretResult::InterpretRuntimeError);
+                }
+
+                if tail && !self.frames.is_empty() {
+                    // Moves the callee and its arguments down over this frame
+                    let frame_len = self.stack.len() - self.slots;
+                    self.drain_stack(arg_count + 1..frame_len);
+                    self.scopes.truncate(self.scope_base);
+                    self.chunk = Some(Rc::clone(&function.chunk));
+                    self.function = Some(Rc::clone(&obj));
+                    #[cfg(feature = "unchecked")]
+                    {
+                        self.verified = self.is_verified(&obj);
+                    }
+                } else {
+                    if self.frames.len() == FRAMES_MAX {
+                        self.runtime_error("Stack overflow.".to_string());
+                        return Err(InterpretResult::InterpretRuntimeError);
+                    }
+
+                    self.frames.push(CallFrame {
+                        chunk: self.chunk.replace(Rc::clone(&function.chunk)),
+                        function: self.function.replace(Rc::clone(&obj)),
+                        ip: self.ip,
+                        instruction_start: self.instruction_start,
+                        slots: self.slots,
+                        #[cfg(feature = "unchecked")]
+                        verified: self.verified,
+                        scope_base: self.scope_base,
+                    });
+                    #[cfg(feature = "unchecked")]
+                    {
+                        self.verified = self.is_verified(&obj);
+                    }
+                    self.scope_base = self.scopes.len();
+                }
+                // Skips the defaults of the arguments that were passed
+                self.ip = function.entry_points[arg_count - min_arity];
+                self.slots = self.stack.len() - arg_count - 1;
+                return Ok(());
+            }
+            Obj::ObjNative(native) => return self.call_native(native, arg_count),
+            _ => {
+                self.runtime_error("Can only call functions.".to_string());
+                return Err(InterpretResult::InterpretRuntimeError);
+            }
+        }
+    }
+
+    fn call_native(&mut self, native: &ObjNative, arg_count: usize) -> Result<(), InterpretResult> {
+        if arg_count != native.arity as usize {
+            self.runtime_error(format!(
+                "Expected {} arguments but got {} in call to '{}'.",
+                native.arity, arg_count, native.name
+            ));
+            return Err(InterpretResult::InterpretRuntimeError);
+        }
+
+        // The arguments sit above the callee, last one on top
+        let mut args = self.drain_stack(..arg_count);
+        args.reverse();
+        match (native.function)(&args) {
+            Ok(result) => {
+                self.pop_stack();
+                self.push_stack(result);
+                return Ok(());
+            }
+            Err(NativeError::Error(message)) => {
+                self.runtime_error(message);
+                return Err(InterpretResult::InterpretRuntimeError);
+            }
+            Err(NativeError::Exit(code)) => {
+                self.reset_stack();
+                return Err(InterpretResult::InterpretExit(code));
+            }
+        }
+    }
+
+    // Locals are numbered from the current function's slot 0.
+    fn local_slot(&mut self, slot: usize) -> Option<&mut Value> {
+        let index = self.stack.len().checked_sub(self.slots + slot + 1)?;
+        return self.stack.get_mut(index);
+    }
+
+    // Removes `range` of the stack, counted from the top.
+    fn drain_stack<R: std::ops::RangeBounds<usize>>(&mut self, range: R) -> Vec<Value> {
+        let drained: Vec<Value> = self.stack.drain(range).collect();
+        let size: usize = drained
+            .iter()
+            .map(|value| value.approximate_size_bytes())
+            .sum();
+        self.bytes_allocated = self.bytes_allocated.saturating_sub(size);
+        return drained;
+    }
+
+    fn peek_stack_mut(&mut self, distance: usize) -> Option<&mut Value> {
+        return self.stack.get_mut(distance);
     }
 
     fn is_falsey(&self, value: &Value) -> bool {
         return value.is_nil() || (value.is_bool() && !value.as_bool());
     }
 
+    // Output is best effort, a failing writer doesn't stop the script.
+    fn write_output(&self, text: &str) {
+        match &self.output.0 {
+            Some(output) => {
+                let mut output = output.borrow_mut();
+                let _ = output.write_all(text.as_bytes());
+                let _ = output.flush();
+            }
+            None => {
+                let mut stdout = std::io::stdout();
+                let _ = stdout.write_all(text.as_bytes());
+                let _ = stdout.flush();
+            }
+        }
+    }
+
+    // Defines or overwrites a global before or between runs, like `var`
+    // at the top level would.
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        self.set_global(name, value);
+    }
+
+    fn set_global(&mut self, name: &str, value: Value) {
+        let previous = self.globals.insert(name.to_string(), value.clone());
+        if let Some(watchers) = self.watchers.0.get_mut(name) {
+            for watcher in watchers {
+                watcher(previous.as_ref(), &value);
+            }
+        }
+    }
+
     fn reset_stack(&mut self) {
         self.stack.clear();
+        self.scopes.clear();
+        self.scope_base = 0;
+        self.bytes_allocated = 0;
+        self.frames.clear();
+    }
+
+    // Strings are interned for the lifetime of the vm, so the same literal on
+    // different REPL lines (each with its own chunk) shares one object.
+    pub fn intern(&mut self, chars: &str) -> Rc<Obj> {
+        if let Some(obj) = self.strings.get(chars) {
+            return Rc::clone(obj);
+        }
+
+        let obj = Rc::new(Obj::ObjString(chars.to_string()));
+        self.strings.insert(chars.to_string(), Rc::clone(&obj));
+        return obj;
+    }
+
+    fn define_natives(&mut self) {
+        if !self.stdlib {
+            return;
+        }
+        let host_natives: &[(&str, u8, NativeFn)] = match self.sandboxed {
+            true => &[],
+            false => &HOST_NATIVES,
+        };
+        for &(name, arity, function) in NATIVES.iter().chain(host_natives) {
+            let native = ObjNative {
+                name: name.to_string(),
+                arity,
+                function,
+            };
+            self.globals.insert(
+                name.to_string(),
+                Value::from_obj(Rc::new(Obj::ObjNative(native))),
+            );
+        }
+    }
+
+    // Function chunks are shared and never mutated, so a function with
+    // strings to intern is replaced by a copy holding the interned ones.
+    fn intern_constants(&mut self, chunk: &mut Chunk) {
+        for constant in chunk.constants.iter_mut() {
+            if constant.is_string() {
+                *constant = Value::from_obj(self.intern(constant.as_string()));
+            } else if let Some(Obj::ObjFunction(function)) = constant.as_obj().map(|obj| &**obj) {
+                let mut function_chunk = Chunk::clone(&function.chunk);
+                self.intern_constants(&mut function_chunk);
+                let interned = ObjFunction {
+                    arity: function.arity,
+                    min_arity: function.min_arity,
+                    entry_points: function.entry_points.clone(),
+                    upvalue_count: function.upvalue_count,
+                    chunk: Rc::new(function_chunk),
+                    name: function.name.clone(),
+                };
+                *constant = Value::from_obj(Rc::new(Obj::ObjFunction(interned)));
+            }
+        }
     }
 
     fn values_equal(&self, a: Value, b: Value) -> bool {
@@ -237,36 +1330,807 @@ impl Vm {
             ValueType::ValBool => return a.as_bool() == b.as_bool(),
             ValueType::ValNil => return true,
             ValueType::ValNumber => return a.as_number() == b.as_number(),
+            ValueType::ValObj => {
+                if a.is_string() && b.is_string() {
+                    return a.as_string() == b.as_string();
+                }
+
+                // Lists are equal when their elements are, lists never
+                // change so they can't contain themselves.
+                if let (Some(Obj::ObjList(a)), Some(Obj::ObjList(b))) =
+                    (a.as_obj().map(|obj| &**obj), b.as_obj().map(|obj| &**obj))
+                {
+                    return a.len() == b.len()
+                        && a.iter()
+                            .zip(b)
+                            .all(|(a, b)| self.values_equal(a.clone(), b.clone()));
+                }
+
+                match (a.as_obj(), b.as_obj()) {
+                    (Some(a), Some(b)) => return Rc::ptr_eq(a, b),
+                    _ => return false,
+                }
+            }
+        }
+    }
+
+    // Names of the globals holding Lox functions, sorted.
+    pub fn global_functions(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .globals
+            .iter()
+            .filter(|(_, value)| value.is_obj_type(ObjType::ObjFunction))
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        return names;
+    }
+
+    // Error reported by the last run, `None` if it had none.
+    pub fn last_error(&self) -> Option<&RuntimeError> {
+        return self.last_error.as_ref();
+    }
+
+    // Line of the instruction being executed, 0 without a chunk.
+    fn current_line(&self) -> i32 {
+        match &self.chunk {
+            Some(chunk) => return chunk.position(self.instruction_start).0,
+            None => return 0,
+        }
+    }
+
+    // `is` compares lists and functions by identity, everything else the
+    // same way as `==`. Strings are interned, so two equal strings are
+    // treated as the same object.
+    fn values_identical(&self, a: Value, b: Value) -> bool {
+        if a.is_string() && b.is_string() {
+            return a.as_string() == b.as_string();
+        }
+
+        match (a.as_obj(), b.as_obj()) {
+            (Some(a), Some(b)) => return Rc::ptr_eq(a, b),
+            (None, None) => return self.values_equal(a, b),
+            _ => return false,
         }
     }
 
     fn runtime_error(&mut self, msg: String) {
         println!("{}", msg);
+        self.last_error = Some(RuntimeError {
+            message: msg.clone(),
+            line: self.current_line(),
+        });
 
-        if let Some(chunk) = self.chunk.take() {
-            let line = chunk.lines[self.ip];
-            println!("[line {}] in script\n", line);
-            self.chunk = Some(chunk);
+        // Innermost call first
+        let mut trace = vec![(&self.chunk, &self.function, self.instruction_start)];
+        for frame in self.frames.iter().rev() {
+            trace.push((&frame.chunk, &frame.function, frame.instruction_start));
         }
 
+        for (chunk, function, offset) in trace {
+            let location = match function.as_deref() {
+                Some(Obj::ObjFunction(function)) => format!("{}()", function.display_name()),
+                _ => "script".to_string(),
+            };
+
+            if let Some(chunk) = chunk {
+                match chunk.position(offset) {
+                    (line, 0) => println!("[line {}] in {}", line, location),
+                    (line, column) => {
+                        println!("[line {}, column {}] in {}", line, column, location)
+                    }
+                }
+            }
+        }
+        println!();
+
         self.reset_stack();
     }
 
+    // Always bounds checked, `ip` is past the end once the script returned.
+    fn read_op_code(&mut self) -> Result<u8, InterpretResult> {
+        match self.chunk.as_ref().and_then(|chunk| chunk.byte_at(self.ip)) {
+            Some(byte) => {
+                self.ip += 1;
+                return Ok(byte);
+            }
+            None => {
+                self.runtime_error("Instruction pointer out of bounds.".to_string());
+                return Err(InterpretResult::InterpretRuntimeError);
+            }
+        }
+    }
+
     fn read_byte(&mut self) -> Result<u8, InterpretResult> {
-        if let Some(chunk) = &self.chunk {
-            let byte = chunk.code[self.ip];
-            self.ip += 1;
-            return Ok(byte);
+        let byte = match &self.chunk {
+            // Safety: operands are only read after their op code, and verified
+            // chunks hold complete instructions.
+            #[cfg(feature = "unchecked")]
+            Some(chunk) if self.verified => unsafe { Some(chunk.byte_at_unchecked(self.ip)) },
+            Some(chunk) => chunk.byte_at(self.ip),
+            None => None,
+        };
+
+        match byte {
+            Some(byte) => {
+                self.ip += 1;
+                return Ok(byte);
+            }
+            None => {
+                self.runtime_error("Instruction pointer out of bounds.".to_string());
+                return Err(InterpretResult::InterpretRuntimeError);
+            }
+        }
+    }
+
+    fn read_short(&mut self) -> Result<u16, InterpretResult> {
+        let high = self.read_byte()? as u16;
+        let low = self.read_byte()? as u16;
+        return Ok(high << 8 | low);
+    }
+
+    // Long variants of the global instructions index the constants with
+    // three bytes.
+    fn read_global_name(&mut self, operation: OpCode) -> Result<Value, InterpretResult> {
+        match operation {
+            OpCode::OpDefineGlobalLong | OpCode::OpGetGlobalLong | OpCode::OpSetGlobalLong => {
+                return self.read_constant_long();
+            }
+            _ => return self.read_constant(),
+        }
+    }
+
+    fn read_constant_long(&mut self) -> Result<Value, InterpretResult> {
+        let index = (self.read_byte()? as usize) << 16
+            | (self.read_byte()? as usize) << 8
+            | self.read_byte()? as usize;
+        let constant = self
+            .chunk
+            .as_ref()
+            .and_then(|chunk| chunk.constants.get(index))
+            .cloned();
+        match constant {
+            Some(constant) => return Ok(constant),
+            None => {
+                self.runtime_error("Constant out of bounds.".to_string());
+                return Err(InterpretResult::InterpretRuntimeError);
+            }
         }
-        return Err(InterpretResult::InterpretRuntimeError);
     }
 
     fn read_constant(&mut self) -> Result<Value, InterpretResult> {
-        if let Some(chunk) = &self.chunk {
-            let constant = chunk.constants[chunk.code[self.ip] as usize].clone();
-            self.ip += 1;
-            return Ok(constant);
+        let constant = match &self.chunk {
+            // Safety: verified chunks only refer to constants that exist.
+            #[cfg(feature = "unchecked")]
+            Some(chunk) if self.verified => unsafe {
+                let index = chunk.byte_at_unchecked(self.ip) as usize;
+                Some(chunk.constants.get_unchecked(index).clone())
+            },
+            Some(chunk) => chunk
+                .byte_at(self.ip)
+                .and_then(|index| chunk.constants.get(index as usize))
+                .cloned(),
+            None => None,
+        };
+
+        match constant {
+            Some(constant) => {
+                self.ip += 1;
+                return Ok(constant);
+            }
+            None => {
+                self.runtime_error("Constant out of bounds.".to_string());
+                return Err(InterpretResult::InterpretRuntimeError);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            return self.0.borrow_mut().write(buf);
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    // Output and result of running `source` on a fresh vm, `prepare` gets
+    // the vm once the chunk is loaded.
+    fn run_with(source: &str, prepare: impl Fn(&mut Vm)) -> (String, String) {
+        let output = Rc::new(RefCell::new(vec![]));
+        let mut vm = Vm::new().with_output(SharedOutput(Rc::clone(&output)));
+        vm.load_chunk(compile(source).unwrap()).unwrap();
+        prepare(&mut vm);
+        let result = format!("{:?}", vm.run());
+        let output = String::from_utf8(output.borrow().clone()).unwrap();
+        return (output, result);
+    }
+
+    const PROGRAMS: [&str; 5] = [
+        "print 1 + 2 * 3, -4 / 2, 7 % 3;",
+        "fun add(a, b = 10) { return a + b; } print add(1), add(1, 2);",
+        "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } print fib(12);",
+        "var s = 0; for (var i = 0; i < 3; i = i + 1) { s = s + i; } print s, 0 .. 3, \"done\";",
+        "fun f() { return nil + 1; } print 1; f();",
+    ];
+
+    #[cfg(feature = "unchecked")]
+    #[test]
+    fn checked_and_unchecked_modes_agree() {
+        for source in PROGRAMS {
+            let unchecked = run_with(source, |vm| assert!(vm.verified));
+            let checked = run_with(source, |vm| {
+                vm.verified = false;
+                vm.verified_functions.clear();
+            });
+            assert_eq!(unchecked, checked, "{}", source);
+        }
+    }
+
+    #[test]
+    fn programs_run_to_the_expected_result() {
+        let results: Vec<String> = PROGRAMS
+            .iter()
+            .map(|source| run_with(source, |_| ()).1)
+            .collect();
+        assert_eq!(results[..4], ["Ok(Returned(Value { ValNil }))"; 4]);
+        assert_eq!(results[4], "Err(InterpretRuntimeError)");
+    }
+
+    #[test]
+    fn interpret_source_returns_compile_diagnostics() {
+        match Vm::new().interpret_source("print 1 +;\nvar = 2;".to_string()) {
+            Err(LoxError::Compile(errors)) => assert_eq!(errors.len(), 2),
+            result => panic!("Expected compile errors, got {:?}.", result),
+        }
+    }
+
+    #[test]
+    fn invalid_chunk_is_rejected_on_load() {
+        let mut vm = Vm::new();
+        let chunk = Chunk::from_code(vec![OpCode::OpConstant as u8, 0, OpCode::OpReturn as u8]);
+        assert!(matches!(
+            vm.load_chunk(chunk),
+            Err(InterpretResult::InterpretRuntimeError)
+        ));
+        assert!(matches!(
+            vm.step(),
+            Err(InterpretResult::InterpretRuntimeError)
+        ));
+    }
+
+    fn eval(source: &str) -> Result<(), LoxError> {
+        return Vm::new().interpret_source(source.to_string());
+    }
+
+    fn range(source: &str) -> String {
+        let value = crate::lox::Lox::new().eval(source).unwrap();
+        return value.display_with_format(&ValueFormat::default());
+    }
+
+    fn type_of(source: &str) -> String {
+        let source = format!("typeof {}", source);
+        let value = crate::lox::Lox::new().eval(&source).unwrap();
+        return value.as_string().to_string();
+    }
+
+    #[test]
+    fn typeof_names_each_value_type() {
+        assert_eq!(type_of("1.5"), "number");
+        assert_eq!(type_of("true"), "bool");
+        assert_eq!(type_of("nil"), "nil");
+        assert_eq!(type_of("\"a\""), "string");
+        assert_eq!(type_of("(0 .. 2)"), "list");
+        assert_eq!(type_of("fun () {}"), "function");
+        assert_eq!(type_of("sqrt"), "native function");
+        assert_eq!(type_of("typeof nil"), "string");
+    }
+
+    fn number(source: &str) -> f64 {
+        return crate::lox::Lox::new().eval(source).unwrap().as_number();
+    }
+
+    #[test]
+    fn shifts_work_on_integers() {
+        assert_eq!(number("1 << 4"), 16.0);
+        assert_eq!(number("-16 >> 2"), -4.0);
+        assert_eq!(number("-16 >>> 60"), 15.0);
+        assert_eq!(number("-1 >>> 0"), u64::MAX as f64);
+    }
+
+    #[test]
+    fn shift_amount_wraps_around_modulo_64() {
+        assert_eq!(number("1 << 64"), 1.0);
+        assert_eq!(number("1 << 65"), 2.0);
+        assert_eq!(number("-8 >> -1"), -1.0);
+    }
+
+    #[test]
+    fn range_counts_up_from_start() {
+        assert_eq!(range("1 .. 4"), "[1, 2, 3]");
+        assert_eq!(range("1 ..= 3"), "[1, 2, 3]");
+        assert_eq!(range("-2 .. 0"), "[-2, -1]");
+        assert_eq!(range("3 .. 1"), "[]");
+    }
+
+    #[test]
+    fn range_bounds_must_be_integers() {
+        assert!(eval("print 0 .. 3;").is_ok());
+        for source in ["print 0.5 .. 3;", "print 0 ..= 2.5;"] {
+            assert!(matches!(
+                eval(source),
+                Err(LoxError::Interpret(
+                    InterpretResult::InterpretTypeMismatch { line: 1 }
+                ))
+            ));
+        }
+    }
+
+    fn boolean(source: &str) -> bool {
+        return crate::lox::Lox::new().eval(source).unwrap().as_bool();
+    }
+
+    #[test]
+    fn in_searches_a_list() {
+        assert!(boolean("1 in (1 ..= 3)"));
+        assert!(boolean("3 in (1 ..= 3)"));
+        assert!(!boolean("4 in (1 ..= 3)"));
+        assert!(!boolean("\"1\" in (1 ..= 3)"));
+        assert!(!boolean("1 in (1 .. 1)"));
+    }
+
+    #[test]
+    fn in_needs_a_list_on_the_right() {
+        for source in [
+            "print 1 in 5;",
+            "print \"a\" in \"abc\";",
+            "print 1 in nil;",
+        ] {
+            assert!(matches!(
+                eval(source),
+                Err(LoxError::Interpret(InterpretResult::InterpretRuntimeError))
+            ));
+        }
+    }
+
+    #[test]
+    fn range_length_is_capped() {
+        assert!(eval("print 1 .. 1;").is_ok());
+        assert!(eval("print 3 .. 1;").is_ok());
+        assert!(matches!(
+            eval("print 0 .. 100000000000;"),
+            Err(LoxError::Interpret(InterpretResult::InterpretRuntimeError))
+        ));
+    }
+
+    #[test]
+    fn zero_divisor_is_a_division_by_zero() {
+        for source in [
+            "print 1 / 0;",
+            "print 5 % 0;",
+            "var zero = 0;\nprint 1 / -zero;",
+        ] {
+            let line = source.lines().count() as i32;
+            assert!(matches!(
+                eval(source),
+                Err(LoxError::Interpret(
+                    InterpretResult::InterpretDivisionByZero { line: error_line }
+                )) if error_line == line
+            ));
+        }
+        assert!(eval("print 0 / 5, 5 % 3;").is_ok());
+        assert!(matches!(
+            eval("print nil / 0;"),
+            Err(LoxError::Interpret(InterpretResult::InterpretRuntimeError))
+        ));
+    }
+
+    #[test]
+    fn shift_operands_must_be_integers() {
+        for source in ["print 1.5 << 1;", "print 1 >> 0.5;", "print nil >>> 1;"] {
+            let mut vm = Vm::new();
+            assert!(matches!(
+                vm.interpret_source(source.to_string()),
+                Err(LoxError::Interpret(
+                    InterpretResult::InterpretTypeMismatch { line: 1 }
+                ))
+            ));
+            assert_eq!(
+                vm.last_error().unwrap().message,
+                "Operand must be an integer."
+            );
+        }
+    }
+
+    #[test]
+    fn global_functions_are_listed_by_name() {
+        let mut vm = Vm::new();
+        let source = "fun test_b() {} var x = 1; fun a() {} var test_c = fun () {};";
+        assert!(vm.interpret_source(source.to_string()).is_ok());
+        assert_eq!(vm.global_functions(), ["a", "test_b", "test_c"]);
+    }
+
+    #[test]
+    fn strings_in_functions_are_interned_across_runs() {
+        let mut vm = Vm::new();
+        let first = "fun f() { return \"x\"; } var a = f();".to_string();
+        assert!(vm.interpret_source(first).is_ok());
+        assert!(vm.interpret_source("var b = \"x\";".to_string()).is_ok());
+
+        match (vm.globals["a"].as_obj(), vm.globals["b"].as_obj()) {
+            (Some(a), Some(b)) => assert!(Rc::ptr_eq(a, b)),
+            _ => panic!("Expected two strings."),
+        }
+    }
+
+    fn runtime_error_message(source: &str) -> String {
+        let mut vm = Vm::new();
+        assert!(vm.interpret_source(source.to_string()).is_err());
+        return vm.last_error().unwrap().message.clone();
+    }
+
+    #[test]
+    fn arity_mismatch_names_the_callee() {
+        let add = "fun add(a, b) { return a + b; }";
+        assert_eq!(
+            runtime_error_message(&format!("{} add(1);", add)),
+            "Expected 2 arguments but got 1 in call to 'add'."
+        );
+        assert_eq!(
+            runtime_error_message(&format!("{} add(1, 2, 3);", add)),
+            "Expected 2 arguments but got 3 in call to 'add'."
+        );
+        assert_eq!(
+            runtime_error_message("fun f(a, b = 1) {} f();"),
+            "Expected 1 to 2 arguments but got 0 in call to 'f'."
+        );
+    }
+
+    #[cfg(feature = "unchecked")]
+    #[test]
+    fn verified_functions_do_not_outlive_their_chunk() {
+        let mut vm = Vm::new();
+        vm.interpret_source("{ fun f() {} f(); }".to_string())
+            .unwrap();
+        assert_eq!(vm.verified_functions.len(), 1);
+        vm.interpret_source("fun g() {}".to_string()).unwrap();
+        // Only `g`, which the globals keep alive
+        assert_eq!(vm.verified_functions.len(), 1);
+        vm.reset(true);
+        assert!(vm.verified_functions.is_empty());
+    }
+
+    #[test]
+    fn stepping_through_an_addition() {
+        let mut vm = Vm::new();
+        vm.load_chunk(compile("1 + 2;").unwrap()).unwrap();
+        // OP_ONE, OP_CONSTANT, OP_ADD, OP_POP, OP_RETURN
+        let mut steps = 0;
+        loop {
+            steps += 1;
+            match vm.step().unwrap() {
+                StepResult::Returned(value) => {
+                    assert!(value.is_nil());
+                    break;
+                }
+                _ => {
+                    if steps == 3 {
+                        assert_eq!(vm.ip(), 4);
+                        assert_eq!(vm.inspect_stack().len(), 1);
+                        assert_eq!(vm.peek_stack(0).unwrap().as_number(), 3.0);
+                    }
+                }
+            }
+        }
+        assert_eq!(steps, 5);
+        assert!(vm.inspect_stack().is_empty());
+    }
+
+    #[test]
+    fn step_after_return_is_an_error() {
+        let mut vm = Vm::new();
+        vm.load_chunk(compile("print 1;").unwrap()).unwrap();
+        while let Ok(StepResult::Continue) = vm.step() {}
+        assert!(matches!(
+            vm.step(),
+            Err(InterpretResult::InterpretRuntimeError)
+        ));
+    }
+
+    #[test]
+    fn function_from_an_unverified_chunk_is_bounds_checked() {
+        let chunk = Chunk::from_code(vec![OpCode::OpConstant as u8, 200]);
+        let function = ObjFunction {
+            arity: 0,
+            min_arity: 0,
+            entry_points: vec![0],
+            upvalue_count: 0,
+            chunk: Rc::new(chunk),
+            name: Some("broken".to_string()),
+        };
+        let mut vm = Vm::new();
+        vm.define_global(
+            "broken",
+            Value::from_obj(Rc::new(Obj::ObjFunction(function))),
+        );
+        let result = vm.interpret_source("broken();".to_string());
+        assert!(matches!(
+            result,
+            Err(LoxError::Interpret(InterpretResult::InterpretRuntimeError))
+        ));
+    }
+
+    #[test]
+    fn breakpoint_pauses_before_its_instruction_and_resumes() {
+        let mut vm = Vm::new();
+        vm.load_chunk(compile("print 1 + 2;").unwrap()).unwrap();
+        // OP_ONE, OP_CONSTANT at 1, OP_ADD at 3
+        vm.set_breakpoint(3);
+        assert!(matches!(vm.run(), Ok(StepResult::Paused(3))));
+        let stack = vm.inspect_stack();
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack[0].as_number(), 1.0);
+        assert_eq!(stack[1].as_number(), 2.0);
+
+        assert!(matches!(vm.run(), Ok(StepResult::Returned(_))));
+        assert!(vm.inspect_stack().is_empty());
+    }
+
+    #[test]
+    fn watcher_sees_old_and_new_values() {
+        type Changes = Vec<(Option<f64>, f64)>;
+        let seen: Rc<RefCell<Changes>> = Rc::new(RefCell::new(Vec::new()));
+        let recorder = Rc::clone(&seen);
+        let mut vm = Vm::new();
+        vm.watch_global(
+            "a",
+            Box::new(move |old, new| {
+                recorder
+                    .borrow_mut()
+                    .push((old.map(|value| value.as_number()), new.as_number()));
+            }),
+        );
+        vm.interpret_source("var a = 1; a = 5; var b = 7;".to_string())
+            .unwrap();
+        assert_eq!(*seen.borrow(), vec![(None, 1.0), (Some(1.0), 5.0)]);
+    }
+
+    #[test]
+    fn clone_doesnt_share_globals_with_the_original() {
+        let mut vm = Vm::new();
+        assert!(vm.interpret_source("var x = 1;".to_string()).is_ok());
+        let mut snapshot = vm.clone();
+
+        assert!(snapshot
+            .interpret_source("x = 2; var y = 3;".to_string())
+            .is_ok());
+        assert_eq!(vm.globals["x"].as_number(), 1.0);
+        assert!(!vm.globals.contains_key("y"));
+        assert_eq!(snapshot.globals["x"].as_number(), 2.0);
+    }
+
+    #[test]
+    fn clone_resumes_from_the_same_point() {
+        let mut vm = Vm::new();
+        let source = "var a = 1; var b = a + 2; var c = b * 3;";
+        vm.load_chunk(compile(source).unwrap()).unwrap();
+        for _ in 0..4 {
+            assert!(matches!(vm.step(), Ok(StepResult::Continue)));
+        }
+        let (ip, depth) = (vm.ip(), vm.stack.len());
+        let mut snapshot = vm.clone();
+
+        assert!(snapshot.run().is_ok());
+        assert_eq!((vm.ip(), vm.stack.len()), (ip, depth));
+        assert!(!vm.globals.contains_key("c"));
+        assert!(vm.run().is_ok());
+        assert_eq!(vm.globals["c"].as_number(), 9.0);
+        assert_eq!(snapshot.globals["c"].as_number(), 9.0);
+    }
+
+    #[test]
+    fn timeout_stops_every_run_after_it_is_set() {
+        let mut vm = Vm::new();
+        vm.set_timeout(Duration::from_millis(20));
+        for _ in 0..2 {
+            let result = vm.interpret_source("while (true) {}".to_string());
+            assert!(matches!(
+                result,
+                Err(LoxError::Interpret(InterpretResult::InterpretTimeout))
+            ));
         }
-        return Err(InterpretResult::InterpretRuntimeError);
+    }
+
+    #[test]
+    fn run_after_a_timeout_is_not_interrupted() {
+        let mut vm = Vm::new();
+        vm.set_timeout(Duration::from_millis(20));
+        let result = vm.interpret_source("while (true) {}".to_string());
+        assert!(matches!(
+            result,
+            Err(LoxError::Interpret(InterpretResult::InterpretTimeout))
+        ));
+
+        // Long enough to pass a limit check.
+        vm.set_timeout(Duration::from_secs(60));
+        let source = "for (var i = 0; i < 2000; i = i + 1) {}".to_string();
+        assert!(vm.interpret_source(source).is_ok());
+        assert!(vm.timer.is_none());
+        assert!(!vm.interrupted.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn reset_for_next_clears_an_interrupt() {
+        let mut vm = Vm::new();
+        vm.set_timeout(Duration::from_secs(60));
+        vm.interrupted.store(true, Ordering::Relaxed);
+        vm.reset_for_next();
+        assert!(!vm.interrupted.load(Ordering::Relaxed));
+
+        vm.cancel_timeout();
+        let source = "for (var i = 0; i < 2000; i = i + 1) {}".to_string();
+        assert!(vm.interpret_source(source).is_ok());
+    }
+
+    #[test]
+    fn instruction_limit_stops_an_infinite_loop() {
+        let mut vm = Vm::new().with_max_instructions(10000);
+        let result = vm.interpret_source("while (true) {}".to_string());
+        assert!(matches!(
+            result,
+            Err(LoxError::Interpret(
+                InterpretResult::InterpretExecutionLimitExceeded
+            ))
+        ));
+        assert!(vm.instruction_count <= 10000 + LIMIT_CHECK_INTERVAL);
+    }
+
+    #[test]
+    fn instruction_limit_counts_each_run_separately() {
+        let mut vm = Vm::new().with_max_instructions(10000);
+        // A few thousand instructions, twice is over the limit
+        let source = "for (var i = 0; i < 500; i = i + 1) {}";
+        for _ in 0..3 {
+            assert!(vm.interpret_source(source.to_string()).is_ok());
+        }
+    }
+
+    #[test]
+    fn getenv_reads_the_environment() {
+        std::env::set_var("LOX_VM_TEST_GETENV", "from the host");
+        std::env::remove_var("LOX_VM_TEST_UNSET");
+        let (output, result) = run_with(
+            "print getenv(\"LOX_VM_TEST_GETENV\"), getenv(\"LOX_VM_TEST_UNSET\");",
+            |_| (),
+        );
+        assert_eq!(output, "from the host nil\n");
+        assert!(result.starts_with("Ok(Returned"), "{}", result);
+
+        assert_eq!(
+            runtime_error_message("getenv(1);"),
+            "Argument to 'getenv' must be a string."
+        );
+
+        let mut vm = Vm::new().with_sandbox();
+        let source = "getenv(\"LOX_VM_TEST_GETENV\");".to_string();
+        assert!(vm.interpret_source(source).is_err());
+    }
+
+    #[test]
+    fn failed_assert_is_the_last_error() {
+        let mut vm = Vm::new();
+        assert!(vm.interpret_source("assert(1 < 2);".to_string()).is_ok());
+        assert_eq!(vm.last_error(), None);
+
+        let source = "var a = 1;\nassert(a > 2);".to_string();
+        assert!(vm.interpret_source(source).is_err());
+        let expected = RuntimeError {
+            message: "Assertion failed.".to_string(),
+            line: 2,
+        };
+        assert_eq!(vm.last_error(), Some(&expected));
+    }
+
+    #[test]
+    fn sandbox_refuses_host_natives_but_keeps_math() {
+        let mut vm = Vm::sandboxed();
+        let result = vm.interpret_source("getenv(\"X\");".to_string());
+        assert!(matches!(
+            result,
+            Err(LoxError::Interpret(InterpretResult::InterpretRuntimeError))
+        ));
+        assert_eq!(
+            vm.last_error().unwrap().message,
+            "Operation not permitted in sandbox mode."
+        );
+
+        let output = Rc::new(RefCell::new(vec![]));
+        let mut vm = Vm::sandboxed().with_output(SharedOutput(Rc::clone(&output)));
+        vm.interpret_source("print sqrt(4);".to_string()).unwrap();
+        assert_eq!(output.borrow().as_slice(), b"2\n");
+    }
+
+    #[test]
+    fn exit_stops_the_script_with_its_code() {
+        let output = Rc::new(RefCell::new(vec![]));
+        let mut vm = Vm::new().with_output(SharedOutput(Rc::clone(&output)));
+        let result = vm.interpret_source("print 1; exit(3); print 2;".to_string());
+        assert!(matches!(
+            result,
+            Err(LoxError::Interpret(InterpretResult::InterpretExit(3)))
+        ));
+        assert_eq!(output.borrow().as_slice(), b"1\n");
+
+        assert_eq!(
+            runtime_error_message("exit(1.5);"),
+            "Argument to 'exit' must be an integer."
+        );
+    }
+
+    #[test]
+    fn three_hundred_globals_use_the_long_opcodes() {
+        let mut source: String = (1..=300)
+            .map(|i| format!("var g{} = {};\n", i, i))
+            .collect();
+        source.push_str("g300 = g300 + 1; print g300;");
+        let chunk = compile(&source).unwrap();
+        let listing = chunk.dissasemble_to_string("globals").unwrap();
+        assert!(listing.contains("OP_DEFINE_GLOBAL_LONG"));
+        assert!(listing.contains("OP_SET_GLOBAL_LONG"));
+        assert!(listing.contains("OP_GET_GLOBAL_LONG"));
+
+        let (output, result) = run_with(&source, |_| ());
+        assert_eq!(output, "301\n");
+        assert!(result.starts_with("Ok(Returned"), "{}", result);
+    }
+
+    #[test]
+    fn print_separates_values_with_spaces() {
+        let (output, _) = run_with("print 1, \"x\", true; print nil;", |_| ());
+        assert_eq!(output, "1 x true\nnil\n");
+    }
+
+    // Pushes `push`, asserts it is `expected` and returns it.
+    fn assert_type_chunk(push: OpCode, expected: ValueType) -> Chunk {
+        let mut chunk = Chunk::new();
+        chunk.write_instruction(push, 1);
+        chunk.write_instruction(OpCode::OpAssertType, 1);
+        chunk.write_byte(expected as u8, 1);
+        chunk.write_instruction(OpCode::OpReturn, 1);
+        return chunk;
+    }
+
+    #[test]
+    fn assert_type_passes_on_a_match_and_keeps_the_value() {
+        let mut vm = Vm::new();
+        vm.load_chunk(assert_type_chunk(OpCode::OpOne, ValueType::ValNumber))
+            .unwrap();
+        match vm.run() {
+            Ok(StepResult::Returned(value)) => assert_eq!(value.as_number(), 1.0),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn assert_type_errors_on_a_mismatch() {
+        let mut vm = Vm::new();
+        vm.load_chunk(assert_type_chunk(OpCode::OpNil, ValueType::ValNumber))
+            .unwrap();
+        assert!(matches!(
+            vm.run(),
+            Err(InterpretResult::InterpretRuntimeError)
+        ));
+        assert_eq!(
+            vm.last_error().unwrap().message,
+            "Expected number on top of the stack but got nil."
+        );
     }
 }
@@ -0,0 +1,114 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+// A scratch directory laid out like the repository, `run` and `compile`
+// write their bins to `lox/bin`.
+fn project_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("lox-cli-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("lox/source")).unwrap();
+    fs::create_dir_all(dir.join("lox/bin")).unwrap();
+    return dir;
+}
+
+fn runtime_with_status(dir: &PathBuf, args: &[&str]) -> (Option<i32>, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_runtime"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    return (output.status.code(), stdout);
+}
+
+fn runtime(dir: &PathBuf, args: &[&str]) -> String {
+    return runtime_with_status(dir, args).1;
+}
+
+const FIB: &str = "
+fun fib(n) {
+    if (n < 2) return n;
+    return fib(n - 1) + fib(n - 2);
+}
+var twice = fun (f, x) { return f(f(x)); };
+print fib(10), twice(fib, 5);
+";
+
+#[test]
+fn run_executes_a_program_with_functions() {
+    let dir = project_dir("run");
+    fs::write(dir.join("lox/source/fib.lox"), FIB).unwrap();
+
+    let stdout = runtime(&dir, &["run", "lox/source/fib.lox"]);
+    assert!(stdout.lines().any(|line| line == "55 5"), "{}", stdout);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn compiled_bin_executes_a_program_with_functions() {
+    let dir = project_dir("execute");
+    fs::write(dir.join("lox/source/fib.lox"), FIB).unwrap();
+
+    let stdout = runtime(&dir, &["compile", "lox/source/fib.lox", "--strip-debug"]);
+    assert!(stdout.contains("[DONE]"), "{}", stdout);
+    let stdout = runtime(&dir, &["execute", "lox/bin/fib"]);
+    assert!(stdout.lines().any(|line| line == "55 5"), "{}", stdout);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_runs_each_test_function() {
+    let dir = project_dir("test");
+    let source = "
+fun double(n) { return n * 2; }
+fun test_double() { assert(double(2) == 4); }
+fun test_broken() {
+    assert(double(2) == 5);
+}
+";
+    fs::write(dir.join("lox/source/double.lox"), source).unwrap();
+
+    let (code, stdout) = runtime_with_status(&dir, &["test", "lox/source/double.lox"]);
+    assert_eq!(code, Some(1), "{}", stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert!(lines.contains(&"[PASS]: lox/source/double.lox:test_double"));
+    assert!(lines.contains(&"[FAIL]: lox/source/double.lox:test_broken"));
+    assert!(
+        lines.contains(&"[ERROR]: lox/source/double.lox:test_broken (line 5: Assertion failed.)")
+    );
+    assert!(lines.contains(&"[DONE]: 1 passed, 1 failed"));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_passes_a_directory_without_failures() {
+    let dir = project_dir("test-dir");
+    fs::write(
+        dir.join("lox/source/a.lox"),
+        "fun test_a() { assert(true); }",
+    )
+    .unwrap();
+    fs::write(dir.join("lox/source/b.lox"), "print 1;").unwrap();
+
+    let (code, stdout) = runtime_with_status(&dir, &["test", "lox/source"]);
+    assert_eq!(code, Some(0), "{}", stdout);
+    assert!(stdout
+        .lines()
+        .any(|line| line == "[DONE]: 2 passed, 0 failed"));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn check_exits_65_on_a_syntax_error() {
+    let dir = project_dir("check");
+    fs::write(dir.join("lox/source/good.lox"), "print 1 + 2;").unwrap();
+    fs::write(dir.join("lox/source/bad.lox"), "print 1 +;").unwrap();
+
+    let (code, stdout) = runtime_with_status(&dir, &["check", "lox/source/good.lox"]);
+    assert_eq!(code, Some(0), "{}", stdout);
+    let (code, stdout) = runtime_with_status(&dir, &["check", "lox/source/bad.lox"]);
+    assert_eq!(code, Some(65), "{}", stdout);
+    assert!(stdout.contains("Expect expression."), "{}", stdout);
+    let _ = fs::remove_dir_all(&dir);
+}